@@ -47,7 +47,8 @@
             pname = "openedge";
             version = "0.1.0";
             src = ./.;
-            cargoHash = "sha256-zrwDD5+p2sNTYFrWZO1/wzb43omVPc1CwyA7k5bJm58=";
+            # vendored from the lock, so dependency changes need no hash update
+            cargoLock.lockFile = ./Cargo.lock;
             preBuild = ''
               mkdir -p ./target/x86_64-unknown-linux-gnu/release/gn_out/obj
               cp ${prebuiltRustyV8} ./target/x86_64-unknown-linux-gnu/release/gn_out/obj/librusty_v8.a
@@ -70,6 +71,7 @@
               cp ${self}/hello.js ./
               cp ${self}/goodbye.js ./
               cp ${self}/keys.js ./
+              cp ${self}/openedge.json ./
             '';
             config = {
               Cmd = [ "${default}/bin/openedge" ];
@@ -0,0 +1,303 @@
+//! One log line per request served by the ingress, written once the response
+//! body has been sent. Console output of workers goes to the same stream, see
+//! [crate::console]. Lines are handed to a writer thread so a slow sink never
+//! blocks the isolate threads; when it falls behind, lines are dropped and
+//! counted.
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use deno_runtime::deno_core::error::AnyError;
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{Body, HeaderMap};
+use serde::Serialize;
+
+use crate::config::{AccessLogConfig, LogFormat};
+use crate::console::ConsoleLine;
+
+/// Lines waiting for the writer thread before further ones are dropped.
+const QUEUED_LINES: usize = 8192;
+
+pub struct AccessLog {
+    format: LogFormat,
+    lines: SyncSender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// A log line in JSON mode, told apart by its `type`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonLine<'a> {
+    Request(&'a Entry),
+    Console(&'a ConsoleLine),
+}
+
+impl AccessLog {
+    /// Opens the log described by `config`. A relative path is resolved against
+    /// `base_dir`.
+    pub fn open(config: &AccessLogConfig, base_dir: &Path) -> Result<Self, AnyError> {
+        let sink: Box<dyn Write + Send> = match &config.path {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(base_dir.join(path))?,
+            ),
+            None => Box::new(std::io::stdout()),
+        };
+        let (lines, queued) = mpsc::sync_channel(QUEUED_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = dropped.clone();
+        std::thread::Builder::new()
+            .name("openedge-log".to_string())
+            .spawn(move || write_lines(BufWriter::new(sink), queued, &writer_dropped))?;
+        Ok(AccessLog {
+            format: config.format,
+            lines,
+            dropped,
+        })
+    }
+
+    fn record(&self, entry: &Entry) {
+        let line = match self.format {
+            LogFormat::Json => match serde_json::to_string(&JsonLine::Request(entry)) {
+                Ok(line) => line,
+                Err(_) => return,
+            },
+            LogFormat::Text => format!(
+                "{} {} {} {} {} {} {} {}B {}ms{}{}",
+                entry.timestamp,
+                entry.request_id,
+                entry.client_ip.map_or("-".to_string(), |ip| ip.to_string()),
+                entry.host_slug.as_deref().unwrap_or("-"),
+                entry.method,
+                entry.path,
+                entry.status,
+                entry.bytes,
+                entry.latency_ms,
+                if entry.cold_start { " cold" } else { "" },
+                entry
+                    .isolate_id
+                    .map_or(String::new(), |id| format!(" isolate={id}")),
+            ),
+        };
+        self.write_line(line);
+    }
+
+    pub fn record_console(&self, line: &ConsoleLine) {
+        let line = match self.format {
+            LogFormat::Json => match serde_json::to_string(&JsonLine::Console(line)) {
+                Ok(line) => line,
+                Err(_) => return,
+            },
+            LogFormat::Text => format!(
+                "{} {} {} [{}] {}",
+                line.timestamp,
+                line.request_id.as_deref().unwrap_or("-"),
+                line.host_slug,
+                line.level,
+                line.message,
+            ),
+        };
+        self.write_line(line);
+    }
+
+    fn write_line(&self, line: String) {
+        if let Err(TrySendError::Full(_)) = self.lines.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Runs the writer thread, flushing whenever the queue is empty.
+fn write_lines(mut sink: impl Write, queued: Receiver<String>, dropped: &AtomicU64) {
+    while let Ok(line) = queued.recv() {
+        // a full disk must not take the ingress down
+        let _ = writeln!(sink, "{line}");
+        for line in queued.try_iter() {
+            let _ = writeln!(sink, "{line}");
+        }
+        match dropped.swap(0, Ordering::Relaxed) {
+            0 => {}
+            n => {
+                let _ = writeln!(sink, "{n} log lines dropped, the log could not keep up");
+            }
+        }
+        let _ = sink.flush();
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub timestamp: String,
+    pub request_id: String,
+    pub host_slug: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub latency_ms: u128,
+    pub cold_start: bool,
+    pub isolate_id: Option<u64>,
+    pub client_ip: Option<IpAddr>,
+}
+
+impl Entry {
+    pub fn new(
+        request_id: String,
+        method: String,
+        path: String,
+        client_ip: Option<IpAddr>,
+    ) -> Self {
+        Entry {
+            timestamp: rfc3339(SystemTime::now()),
+            request_id,
+            host_slug: None,
+            method,
+            path,
+            status: 0,
+            bytes: 0,
+            latency_ms: 0,
+            cold_start: false,
+            isolate_id: None,
+            client_ip,
+        }
+    }
+}
+
+/// A response body that counts the bytes sent and writes its request's entry
+/// to the access log once it is done or dropped.
+pub struct LoggedBody {
+    inner: Body,
+    entry: Entry,
+    started: Instant,
+    log: Arc<AccessLog>,
+}
+
+impl LoggedBody {
+    pub fn new(inner: Body, entry: Entry, started: Instant, log: Arc<AccessLog>) -> Self {
+        LoggedBody {
+            inner,
+            entry,
+            started,
+            log,
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.entry.bytes += chunk.len() as u64;
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.latency_ms = self.started.elapsed().as_millis();
+        self.log.record(&self.entry);
+    }
+}
+
+/// Formats `time` as an RFC 3339 timestamp in UTC with millisecond precision.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+    // civil_from_days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64, millis: u64) -> String {
+        rfc3339(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis))
+    }
+
+    fn json(line: JsonLine) -> serde_json::Value {
+        serde_json::from_str(&serde_json::to_string(&line).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn tags_json_lines_with_their_type() {
+        let entry = Entry::new("id".to_string(), "GET".to_string(), "/".to_string(), None);
+        let line = json(JsonLine::Request(&entry));
+        assert_eq!(line["type"], "request");
+        assert_eq!(line["request_id"], "id");
+        let console = ConsoleLine {
+            timestamp: entry.timestamp.clone(),
+            host_slug: "hello".to_string(),
+            request_id: None,
+            level: "log".to_string(),
+            message: "hi".to_string(),
+        };
+        let line = json(JsonLine::Console(&console));
+        assert_eq!(line["type"], "console");
+        assert_eq!(line["message"], "hi");
+    }
+
+    #[test]
+    fn formats_rfc3339() {
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(at(1_709_251_199, 999), "2024-02-29T23:59:59.999Z");
+        assert_eq!(at(4_102_444_800, 0), "2100-01-01T00:00:00.000Z");
+    }
+}
@@ -0,0 +1,641 @@
+//! Automatic certificates for the custom domains of workers. Certificates are
+//! requested from an ACME CA (RFC 8555) with HTTP-01 challenges, which the
+//! ingress answers before routing, and kept in `acme.cert_dir` as
+//! `<domain>.pem` and `<domain>.key`. Certificates are renewed
+//! [RENEW_AFTER] after they were issued. A domain that fails to be issued is
+//! retried with exponential backoff, see [retry_delay].
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use deno_runtime::deno_core::anyhow::anyhow;
+use deno_runtime::deno_core::error::AnyError;
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Body, Client, HeaderMap, Method, Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde_json::{json, Value};
+use tokio_rustls::rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore};
+
+use crate::config::AcmeConfig;
+use crate::store::Store;
+use crate::tls::{self, SniResolver};
+
+/// Requests under this path are answered from [Challenges].
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// CAs such as Let's Encrypt issue certificates for 90 days; renewing after 60
+/// leaves time to retry failures.
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 60;
+
+/// Bounds every request to the CA, including reading the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Backoff after the first failure to issue a certificate, doubling with each
+/// further one. CAs limit failed validations per hostname.
+const RETRY_BASE: Duration = Duration::from_secs(60 * 60);
+const RETRY_MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
+const ACCOUNT_KEY_FILE: &str = "account.p8";
+
+/// Key authorizations of pending HTTP-01 challenges, by token.
+#[derive(Clone, Debug, Default)]
+pub struct Challenges {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl Challenges {
+    /// The key authorization to answer a request for `path` with, if it is a
+    /// pending challenge.
+    pub fn respond(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(CHALLENGE_PATH)?;
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+
+    fn publish(&self, token: &str, key_authorization: String) {
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(token.to_string(), key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens.write().unwrap().remove(token);
+    }
+}
+
+/// Obtains and renews the certificates of every custom domain in the store.
+pub struct CertificateManager {
+    config: AcmeConfig,
+    cert_dir: PathBuf,
+    client: Client<HttpsConnector<HttpConnector>>,
+    store: Store,
+    resolver: Arc<SniResolver>,
+    challenges: Challenges,
+    session: Option<Session>,
+    /// Consecutive failures to issue a domain, and when to retry it.
+    failures: HashMap<String, (u32, Instant)>,
+}
+
+impl CertificateManager {
+    /// Paths in `config` are resolved against `base_dir`.
+    pub fn new(
+        config: &AcmeConfig,
+        base_dir: &Path,
+        store: Store,
+        resolver: Arc<SniResolver>,
+        challenges: Challenges,
+    ) -> Result<Self, AnyError> {
+        let cert_dir = base_dir.join(&config.cert_dir);
+        std::fs::create_dir_all(&cert_dir)?;
+        let client = https_client(config.ca_cert.as_ref().map(|p| base_dir.join(p)).as_deref())?;
+        Ok(CertificateManager {
+            config: config.clone(),
+            cert_dir,
+            client,
+            store,
+            resolver,
+            challenges,
+            session: None,
+            failures: HashMap::new(),
+        })
+    }
+
+    /// Checks the certificates every [CHECK_INTERVAL]. Certificates that fail
+    /// to be issued are retried on the first check after their backoff.
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (domain, _) in self.store.domains() {
+                self.check(&domain).await;
+            }
+        }
+    }
+
+    /// Serves the certificate of `domain` from disk, or requests one if it is
+    /// missing, unreadable or due for renewal.
+    async fn check(&mut self, domain: &str) {
+        let cert_path = self.cert_dir.join(format!("{domain}.pem"));
+        let key_path = self.cert_dir.join(format!("{domain}.key"));
+        let age = std::fs::metadata(&cert_path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if matches!(age, Some(age) if age < RENEW_AFTER) {
+            if self.resolver.contains(domain) {
+                return;
+            }
+            match tls::load_pem_files(&cert_path, &key_path) {
+                Ok(key) => return self.resolver.insert(domain, key),
+                Err(e) => println!("acme: failed to load certificate for {domain}: {e}"),
+            }
+        }
+        if let Some((_, retry_at)) = self.failures.get(domain) {
+            if Instant::now() < *retry_at {
+                return;
+            }
+        }
+
+        println!("acme: requesting certificate for {domain}");
+        match self.issue(domain, &cert_path, &key_path).await {
+            Ok(()) => {
+                self.failures.remove(domain);
+                println!("acme: installed certificate for {domain}");
+            }
+            Err(e) => {
+                let failures = self.failures.get(domain).map_or(0, |(n, _)| *n) + 1;
+                let delay = retry_delay(failures);
+                self.failures
+                    .insert(domain.to_string(), (failures, Instant::now() + delay));
+                println!(
+                    "acme: failed to obtain certificate for {domain}, retrying in {}m: {e}",
+                    delay.as_secs() / 60
+                );
+            }
+        }
+    }
+
+    async fn issue(
+        &mut self,
+        domain: &str,
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> Result<(), AnyError> {
+        if self.session.is_none() {
+            self.session =
+                Some(Session::open(&self.config, &self.cert_dir, self.client.clone()).await?);
+        }
+        let session = self.session.as_mut().unwrap();
+
+        let (headers, order) = session
+            .post(
+                &session.directory.new_order.clone(),
+                Some(&json!({ "identifiers": [{ "type": "dns", "value": domain }] })),
+            )
+            .await?;
+        let order_url = header(&headers, LOCATION.as_str())?;
+        let order: Value = serde_json::from_slice(&order)?;
+
+        for authorization in order["authorizations"].as_array().into_iter().flatten() {
+            let authorization = authorization
+                .as_str()
+                .ok_or_else(|| anyhow!("invalid authorization in order"))?;
+            authorize(session, &self.challenges, authorization).await?;
+        }
+
+        let mut params = rcgen::CertificateParams::new(vec![domain.to_string()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, domain);
+        let key = rcgen::Certificate::from_params(params)?;
+        let finalize = str_field(&order, "finalize")?;
+        session
+            .post(
+                finalize,
+                Some(&json!({ "csr": base64url(&key.serialize_request_der()?) })),
+            )
+            .await?;
+        let order = session.poll(&order_url, "valid").await?;
+        let (_, chain) = session
+            .post(str_field(&order, "certificate")?, None)
+            .await?;
+
+        // the certificate goes last: it is only used while younger than the key
+        write_atomic(key_path, key.serialize_private_key_pem().as_bytes()).await?;
+        write_atomic(cert_path, &chain).await?;
+        self.resolver
+            .insert(domain, tls::load_pem_files(cert_path, key_path)?);
+        Ok(())
+    }
+}
+
+/// How long to wait before retrying a domain after `failures` consecutive
+/// failures to issue it.
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_BASE
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(RETRY_MAX)
+}
+
+/// Completes the HTTP-01 challenge of `authorization`, unless it is already
+/// valid.
+async fn authorize(
+    session: &mut Session,
+    challenges: &Challenges,
+    authorization: &str,
+) -> Result<(), AnyError> {
+    let (_, body) = session.post(authorization, None).await?;
+    let authz: Value = serde_json::from_slice(&body)?;
+    if authz["status"] == "valid" {
+        return Ok(());
+    }
+    let challenge = authz["challenges"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|c| c["type"] == "http-01")
+        .ok_or_else(|| anyhow!("CA offered no http-01 challenge"))?;
+    let token = str_field(challenge, "token")?;
+    challenges.publish(token, format!("{token}.{}", session.thumbprint));
+    let result = async {
+        session
+            .post(str_field(challenge, "url")?, Some(&json!({})))
+            .await?;
+        session.poll(authorization, "valid").await
+    }
+    .await;
+    challenges.remove(token);
+    result.map(|_| ())
+}
+
+#[derive(Debug)]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// An account with the CA, signing requests with its key (JWS, ES256).
+struct Session {
+    client: Client<HttpsConnector<HttpConnector>>,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    /// Of the account key, see RFC 7638.
+    thumbprint: String,
+    /// The account URL, once registered.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Session {
+    /// Registers the account key kept in `cert_dir`, creating it if needed.
+    /// Registering an existing key returns its account.
+    async fn open(
+        config: &AcmeConfig,
+        cert_dir: &Path,
+        client: Client<HttpsConnector<HttpConnector>>,
+    ) -> Result<Self, AnyError> {
+        let req = Request::get(&config.directory_url).body(Body::empty())?;
+        let (status, _, body) = send(&client, req).await?;
+        if !status.is_success() {
+            return Err(anyhow!("{} answered {status}", config.directory_url));
+        }
+        let directory: Value = serde_json::from_slice(&body)?;
+        let directory = Directory {
+            new_nonce: str_field(&directory, "newNonce")?.to_string(),
+            new_account: str_field(&directory, "newAccount")?.to_string(),
+            new_order: str_field(&directory, "newOrder")?.to_string(),
+        };
+
+        let key = account_key(&cert_dir.join(ACCOUNT_KEY_FILE)).await?;
+        let (jwk, thumbprint) = public_jwk(&key);
+
+        let mut session = Session {
+            client,
+            directory,
+            key,
+            rng: SystemRandom::new(),
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &config.email {
+            account["contact"] = json!([format!("mailto:{email}")]);
+        }
+        let (headers, _) = session
+            .post(&session.directory.new_account.clone(), Some(&account))
+            .await?;
+        session.kid = Some(header(&headers, LOCATION.as_str())?);
+        Ok(session)
+    }
+
+    /// Sends a signed request with `payload`, or a POST-as-GET without one.
+    /// Retries once if the CA rejects the nonce.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<(HeaderMap, Bytes), AnyError> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let body = sign(&self.key, &self.rng, &protected, payload)?;
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(serde_json::to_vec(&body)?))?;
+
+            let (status, headers, body) = send(&self.client, req).await?;
+            if let Ok(nonce) = header(&headers, "replay-nonce") {
+                self.nonce = Some(nonce);
+            }
+            if status.is_success() {
+                return Ok((headers, body));
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(anyhow!(
+                "{url} answered {status}: {}",
+                problem["detail"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, AnyError> {
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.directory.new_nonce)
+            .body(Body::empty())?;
+        let (_, headers, _) = send(&self.client, req).await?;
+        header(&headers, "replay-nonce")
+    }
+
+    /// Fetches the order or authorization at `url` until its status is
+    /// `status`.
+    async fn poll(&mut self, url: &str, status: &str) -> Result<Value, AnyError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (_, body) = self.post(url, None).await?;
+            let object: Value = serde_json::from_slice(&body)?;
+            if object["status"] == status {
+                return Ok(object);
+            }
+            if object["status"] == "invalid" {
+                return Err(anyhow!("{url} is invalid: {}", problem_detail(&object)));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(anyhow!("{url} did not become {status} in time"))
+    }
+}
+
+/// Sends `req` and reads the response, failing after [REQUEST_TIMEOUT].
+async fn send(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    req: Request<Body>,
+) -> Result<(StatusCode, HeaderMap, Bytes), AnyError> {
+    let url = req.uri().clone();
+    let exchange = async {
+        let resp = client.request(req).await?;
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok::<_, AnyError>((parts.status, parts.headers, body))
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow!("{url} did not answer in time"))?
+}
+
+/// Signs `payload`, or an empty POST-as-GET payload without one, as a JWS in
+/// flattened JSON serialization (RFC 7515).
+fn sign(
+    key: &EcdsaKeyPair,
+    rng: &SystemRandom,
+    protected: &Value,
+    payload: Option<&Value>,
+) -> Result<Value, AnyError> {
+    let protected = base64url(&serde_json::to_vec(protected)?);
+    let payload = match payload {
+        Some(payload) => base64url(&serde_json::to_vec(payload)?),
+        None => String::new(),
+    };
+    let signature = key
+        .sign(rng, format!("{protected}.{payload}").as_bytes())
+        .map_err(|_| anyhow!("failed to sign ACME request"))?;
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": base64url(signature.as_ref()),
+    }))
+}
+
+/// The JWK of `key` and its thumbprint (RFC 7638).
+fn public_jwk(key: &EcdsaKeyPair) -> (Value, String) {
+    // uncompressed point: 0x04 || x || y
+    let public = key.public_key().as_ref();
+    let (x, y) = (base64url(&public[1..33]), base64url(&public[33..]));
+    let jwk = json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y });
+    (jwk, thumbprint(&x, &y))
+}
+
+fn thumbprint(x: &str, y: &str) -> String {
+    // members in lexicographic order, without whitespace
+    let input = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+    base64url(ring::digest::digest(&ring::digest::SHA256, input.as_bytes()).as_ref())
+}
+
+/// The first error reported for a failed order or authorization.
+fn problem_detail(object: &Value) -> String {
+    let challenge_error = object["challenges"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(|c| c["error"]["detail"].as_str());
+    object["error"]["detail"]
+        .as_str()
+        .or(challenge_error)
+        .unwrap_or("no details")
+        .to_string()
+}
+
+async fn account_key(path: &Path) -> Result<EcdsaKeyPair, AnyError> {
+    let pkcs8 = match tokio::fs::read(path).await {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(
+                &ECDSA_P256_SHA256_FIXED_SIGNING,
+                &SystemRandom::new(),
+            )
+            .map_err(|_| anyhow!("failed to generate ACME account key"))?;
+            write_atomic(path, pkcs8.as_ref()).await?;
+            println!("acme: created account key {}", path.display());
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+        .map_err(|_| anyhow!("invalid ACME account key {}", path.display()))
+}
+
+/// Replaces `path` with a file only the current user can read. The contents
+/// are written to a temporary file first, so `path` is never left partially
+/// written.
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), AnyError> {
+    use tokio::io::AsyncWriteExt;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// A client trusting the usual web roots, and `ca_cert` if given.
+fn https_client(ca_cert: Option<&Path>) -> Result<Client<HttpsConnector<HttpConnector>>, AnyError> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if let Some(path) = ca_cert {
+        let file = File::open(path).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+        for der in rustls_pemfile::certs(&mut BufReader::new(file))? {
+            roots
+                .add(&Certificate(der))
+                .map_err(|e| anyhow!("{}: {e:?}", path.display()))?;
+        }
+    }
+    let tls = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_only()
+        .enable_http1()
+        .build();
+    Ok(Client::builder().build(https))
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Result<String, AnyError> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .ok_or_else(|| anyhow!("CA response has no {name} header"))
+}
+
+fn str_field<'a>(object: &'a Value, name: &str) -> Result<&'a str, AnyError> {
+    object[name]
+        .as_str()
+        .ok_or_else(|| anyhow!("CA response has no {name}"))
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    fn generate_key() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap()
+    }
+
+    fn decode(field: &Value) -> Vec<u8> {
+        base64::decode_config(field.as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    #[test]
+    fn signs_requests_as_jws() {
+        let key = generate_key();
+        let protected = json!({ "alg": "ES256", "nonce": "n", "url": "https://ca/order" });
+        let payload = json!({ "csr": "abc" });
+        for payload in [Some(&payload), None] {
+            let jws = sign(&key, &SystemRandom::new(), &protected, payload).unwrap();
+            let decoded: Value = serde_json::from_slice(&decode(&jws["protected"])).unwrap();
+            assert_eq!(decoded, protected);
+            match payload {
+                Some(payload) => assert_eq!(
+                    serde_json::from_slice::<Value>(&decode(&jws["payload"])).unwrap(),
+                    *payload
+                ),
+                None => assert_eq!(jws["payload"], ""),
+            }
+            let signed = format!(
+                "{}.{}",
+                jws["protected"].as_str().unwrap(),
+                jws["payload"].as_str().unwrap()
+            );
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key.public_key().as_ref())
+                .verify(signed.as_bytes(), &decode(&jws["signature"]))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn computes_key_thumbprint() {
+        // the P-256 key of RFC 7517, appendix A.1
+        assert_eq!(
+            thumbprint(
+                "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+                "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"
+            ),
+            "oKIywvGUpTVTyxMQ3bwIIeQUudfr_CkLMjCE19ECD-U"
+        );
+        let (jwk, thumbprint) = public_jwk(&generate_key());
+        assert_eq!(decode(&jwk["x"]).len(), 32);
+        assert_eq!(decode(&jwk["y"]).len(), 32);
+        assert_eq!(
+            thumbprint,
+            super::thumbprint(jwk["x"].as_str().unwrap(), jwk["y"].as_str().unwrap())
+        );
+    }
+
+    #[test]
+    fn backs_off_failed_domains() {
+        assert_eq!(retry_delay(1), RETRY_BASE);
+        assert_eq!(retry_delay(2), RETRY_BASE * 2);
+        assert_eq!(retry_delay(3), RETRY_BASE * 4);
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX);
+    }
+
+    #[tokio::test]
+    async fn replaces_files_atomically() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("openedge-acme-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.key");
+        write_atomic(&path, b"old").await.unwrap();
+        write_atomic(&path, b"new").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use deno_runtime::deno_core;
+use deno_runtime::deno_core::anyhow::anyhow;
+use deno_runtime::deno_core::error::AnyError;
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio::net::TcpListener;
+
+use crate::threads::IsolateThreads;
+use crate::{config, router, IsolateInfo, LocalExec};
+
+const DEFAULT_ADMIN_PORT: u16 = 9090;
+const DEFAULT_UPLOAD_DIR: &str = "./workers";
+/// Largest worker source accepted for upload.
+const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+/// Configuration of the admin listener, read from the environment.
+///
+/// The admin API is only served when `OPENEDGE_ADMIN_TOKEN` is set. Requests must
+/// carry it as a bearer token.
+pub struct AdminConfig {
+    pub addr: SocketAddr,
+    pub token: String,
+    /// Where worker sources uploaded through the API are written.
+    pub upload_dir: PathBuf,
+}
+
+impl AdminConfig {
+    pub fn from_env() -> Result<Option<Self>, AnyError> {
+        let token = match std::env::var("OPENEDGE_ADMIN_TOKEN") {
+            Ok(t) if !t.is_empty() => t,
+            _ => return Ok(None),
+        };
+        let port = match std::env::var("OPENEDGE_ADMIN_PORT") {
+            Ok(p) => p
+                .parse()
+                .map_err(|e| anyhow!("invalid OPENEDGE_ADMIN_PORT {p:?}: {e}"))?,
+            Err(_) => DEFAULT_ADMIN_PORT,
+        };
+        let upload_dir = std::env::var("OPENEDGE_WORKER_DIR")
+            .unwrap_or_else(|_| DEFAULT_UPLOAD_DIR.to_string())
+            .into();
+        Ok(Some(AdminConfig {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port),
+            token,
+            upload_dir,
+        }))
+    }
+}
+
+pub async fn serve(config: AdminConfig, threads: IsolateThreads) -> Result<(), AnyError> {
+    std::fs::create_dir_all(&config.upload_dir)?;
+    let listener = TcpListener::bind(config.addr).await?;
+    println!("admin listening on {}", config.addr);
+    let config = Rc::new(config);
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let threads = threads.clone();
+        let config = config.clone();
+        let service = service_fn(move |req| handle(config.clone(), threads.clone(), req));
+
+        tokio::task::spawn_local(async move {
+            if let Err(err) = Http::new()
+                .with_executor(LocalExec)
+                .serve_connection(stream, service)
+                .await
+            {
+                println!("Error serving admin connection: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn handle(
+    config: Rc<AdminConfig>,
+    threads: IsolateThreads,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if !is_authorized(&config.token, &req) {
+        return Ok(text(StatusCode::UNAUTHORIZED, "unauthorized\n"));
+    }
+    let segments: Vec<String> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let method = req.method().clone();
+    let resp = match (&method, segments.as_slice()) {
+        (&Method::GET, ["metrics"]) => metrics(&threads).await,
+        (&Method::GET, ["workers"]) => list_workers(&threads).await,
+        (&Method::PUT, ["workers", host_slug]) => {
+            let host_slug = host_slug.to_string();
+            deploy_worker(&config, threads, host_slug, req).await
+        }
+        (&Method::DELETE, ["workers", host_slug]) => delete_worker(&config, threads, host_slug),
+        (&Method::POST, ["workers", host_slug, "restart"]) => {
+            restart_worker(threads, host_slug).await
+        }
+        (&Method::GET, ["workers", host_slug, "tail"]) => tail_worker(threads, host_slug).await,
+        _ => Ok(text(StatusCode::NOT_FOUND, "not found\n")),
+    };
+    Ok(resp.unwrap_or_else(|e| text(StatusCode::INTERNAL_SERVER_ERROR, &format!("{e}\n"))))
+}
+
+fn is_authorized(token: &str, req: &Request<Body>) -> bool {
+    let provided = match req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        Some(t) => t,
+        None => return false,
+    };
+    // compare in constant time so the token can't be recovered byte by byte
+    provided.len() == token.len()
+        && provided
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Metrics in the Prometheus text format, see [crate::metrics].
+async fn metrics(threads: &IsolateThreads) -> Result<Response<Body>, AnyError> {
+    let isolates = threads
+        .run_on_each(|state| async move { state.isolate_samples() })
+        .await?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let body = threads.metrics.render(&isolates, threads.thread_count());
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(body.into())?)
+}
+
+#[derive(Serialize)]
+struct WorkerListing {
+    host_slug: String,
+    module: String,
+    running: Option<IsolateInfo>,
+    crash_looping: bool,
+}
+
+async fn list_workers(threads: &IsolateThreads) -> Result<Response<Body>, AnyError> {
+    let mut listing = vec![];
+    for (host_slug, worker) in threads.store.list() {
+        let slug = host_slug.clone();
+        let (running, crash_looping) = threads
+            .run_for(&host_slug, move |state| async move {
+                (
+                    state.get_running_isolate(&slug).map(|w| w.info()),
+                    state.crashes.is_crash_looping(&slug),
+                )
+            })
+            .await?;
+        listing.push(WorkerListing {
+            running,
+            crash_looping,
+            module: worker.module.to_string(),
+            host_slug,
+        });
+    }
+    json(StatusCode::OK, &listing)
+}
+
+/// Writes the request body to the upload directory and serves it under
+/// `host_slug`. A running isolate for `host_slug` is drained so the next request
+/// runs the new code.
+async fn deploy_worker(
+    config: &AdminConfig,
+    mut threads: IsolateThreads,
+    host_slug: String,
+    req: Request<Body>,
+) -> Result<Response<Body>, AnyError> {
+    if let Err(e) = config::validate_host_slug(&host_slug) {
+        return Ok(text(StatusCode::BAD_REQUEST, &format!("{e}\n")));
+    }
+    let source = match read_upload(req.into_body()).await? {
+        Some(source) => source,
+        None => {
+            return Ok(text(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!("worker source exceeds {MAX_UPLOAD_BYTES} bytes\n"),
+            ))
+        }
+    };
+    let path = config.upload_dir.join(format!("{host_slug}.js"));
+    tokio::fs::write(&path, &source).await?;
+    let module = deno_core::resolve_path(&path.to_string_lossy())?;
+
+    let existed = threads.store.register_module(host_slug.clone(), module);
+    threads.worker_changed(&host_slug);
+    println!("admin: deployed worker {host_slug}");
+
+    let status = if existed {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    Ok(text(status, &format!("deployed {host_slug}\n")))
+}
+
+/// Reads an uploaded worker source, or `None` if it is larger than
+/// [MAX_UPLOAD_BYTES]. Stops reading as soon as the limit is exceeded.
+async fn read_upload(mut body: Body) -> Result<Option<Vec<u8>>, AnyError> {
+    // a Content-Length over the limit is rejected before reading anything
+    if body.size_hint().lower() > MAX_UPLOAD_BYTES as u64 {
+        return Ok(None);
+    }
+    let mut source = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if source.len() + chunk.len() > MAX_UPLOAD_BYTES {
+            return Ok(None);
+        }
+        source.extend_from_slice(&chunk);
+    }
+    Ok(Some(source))
+}
+
+fn delete_worker(
+    config: &AdminConfig,
+    mut threads: IsolateThreads,
+    host_slug: &str,
+) -> Result<Response<Body>, AnyError> {
+    if threads.store.unregister(host_slug).is_none() {
+        return Ok(text(StatusCode::NOT_FOUND, "worker not found\n"));
+    }
+    threads.worker_changed(host_slug);
+    match std::fs::remove_file(config.upload_dir.join(format!("{host_slug}.js"))) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    println!("admin: deleted worker {host_slug}");
+    Ok(text(StatusCode::OK, &format!("deleted {host_slug}\n")))
+}
+
+/// Tears down the running isolate for `host_slug` without draining and
+/// immediately cold starts a replacement.
+async fn restart_worker(
+    threads: IsolateThreads,
+    host_slug: &str,
+) -> Result<Response<Body>, AnyError> {
+    if threads
+        .store
+        .hostslug_to_worker(host_slug.to_string())
+        .is_err()
+    {
+        return Ok(text(StatusCode::NOT_FOUND, "worker not found\n"));
+    }
+    let slug = host_slug.to_string();
+    let worker = threads
+        .run_for(host_slug, move |mut state| async move {
+            state.shutdown_isolate(&slug);
+            router::cold_start(&mut state, &slug, None)
+                .await
+                .map(|w| w.info())
+        })
+        .await??;
+    println!("admin: restarted worker {host_slug}");
+    json(StatusCode::OK, &worker)
+}
+
+/// The most recent console output of `host_slug`, oldest first.
+async fn tail_worker(threads: IsolateThreads, host_slug: &str) -> Result<Response<Body>, AnyError> {
+    if threads
+        .store
+        .hostslug_to_worker(host_slug.to_string())
+        .is_err()
+    {
+        return Ok(text(StatusCode::NOT_FOUND, "worker not found\n"));
+    }
+    let slug = host_slug.to_string();
+    let lines = threads
+        .run_for(
+            host_slug,
+            move |state| async move { state.console_lines(&slug) },
+        )
+        .await?;
+    json(StatusCode::OK, &lines)
+}
+
+fn text(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Result<Response<Body>, AnyError> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(value)?.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_oversized_uploads() {
+        let source = read_upload(Body::from("export default {}")).await.unwrap();
+        assert_eq!(source.as_deref(), Some(&b"export default {}"[..]));
+        let body = Body::from(vec![0; MAX_UPLOAD_BYTES + 1]);
+        assert!(read_upload(body).await.unwrap().is_none());
+        // without a Content-Length, reading stops once the limit is exceeded
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..=MAX_UPLOAD_BYTES / 1024 {
+                if sender.send_data(vec![0; 1024].into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        assert!(read_upload(body).await.unwrap().is_none());
+    }
+}
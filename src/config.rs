@@ -0,0 +1,493 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use deno_runtime::deno_core::anyhow::{self, anyhow};
+use deno_runtime::deno_core::{self, error::AnyError};
+use serde::{Deserialize, Serialize};
+
+use crate::store;
+
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 300;
+pub const DEFAULT_STARTUP_TIMEOUT_MS: u64 = 10_000;
+pub const MAX_STARTUP_TIMEOUT_MS: u64 = 300_000;
+pub const MAX_CPU_MS: u64 = 300_000;
+/// Below this, bootstrapping the runtime alone can exhaust the heap, which V8
+/// treats as a fatal error.
+pub const MIN_HEAP_MB: usize = 32;
+pub const MAX_HEAP_MB: usize = 16_384;
+pub const DEFAULT_CONSOLE_BUFFER_LINES: usize = 100;
+pub const DEFAULT_SERVICE_NAME: &str = "openedge";
+pub const DEFAULT_TLS_PORT: u16 = 8443;
+
+/// The manifest describing every worker served by this ingress, read from the
+/// path given on the command line.
+///
+/// ```json
+/// {
+///   "isolates": { "max_running": 100 },
+///   "workers": {
+///     "hello": { "module": "./hello.js", "env": { "GREETING": "hi" } }
+///   }
+/// }
+/// ```
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub isolates: IsolatesConfig,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    pub tls: Option<TlsConfig>,
+    pub acme: Option<AcmeConfig>,
+    /// Domains whose direct subdomains are routed to the worker of the same
+    /// host slug, e.g. `hello.example.com` to `hello` for `example.com`. Read
+    /// once at startup.
+    #[serde(default)]
+    pub zones: Vec<String>,
+    #[serde(default)]
+    pub workers: BTreeMap<String, WorkerConfig>,
+}
+
+/// Limits applied across all running isolates. Read once at startup.
+///
+/// `max_running` and `max_total_heap_mb` hold across all isolate threads; the
+/// warm pool is split between them, see [IsolatesConfig::warm_pool_per_thread].
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct IsolatesConfig {
+    /// Upper bound on concurrently running isolates. Starting one more evicts
+    /// the least recently used.
+    pub max_running: Option<usize>,
+    /// Upper bound on the heap used by all running isolates together, enforced
+    /// the same way.
+    pub max_total_heap_mb: Option<usize>,
+    /// How long a cold start may take before the isolate is torn down and the
+    /// request fails with a 503. Defaults to [DEFAULT_STARTUP_TIMEOUT_MS], at
+    /// most [MAX_STARTUP_TIMEOUT_MS].
+    pub startup_timeout_ms: Option<u64>,
+    /// Number of bootstrapped runtimes kept ready for cold starts.
+    #[serde(default)]
+    pub warm_pool_size: usize,
+    /// Number of threads running isolates. Defaults to the number of cores.
+    pub threads: Option<usize>,
+    /// Console lines kept per worker for the admin API. Defaults to
+    /// [DEFAULT_CONSOLE_BUFFER_LINES], `0` disables the buffer.
+    pub console_buffer_lines: Option<usize>,
+    /// Runs every isolate in its own sandboxed process, see [crate::sandbox].
+    /// The warm pool is not used in this mode.
+    #[serde(default)]
+    pub sandbox: bool,
+}
+
+impl IsolatesConfig {
+    pub fn startup_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.startup_timeout_ms
+                .unwrap_or(DEFAULT_STARTUP_TIMEOUT_MS),
+        )
+    }
+
+    pub fn console_buffer_lines(&self) -> usize {
+        self.console_buffer_lines
+            .unwrap_or(DEFAULT_CONSOLE_BUFFER_LINES)
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// Runtimes kept ready by each of `threads` isolate threads. Every thread
+    /// cold starts its own workers, so each keeps at least one if any are
+    /// configured.
+    pub fn warm_pool_per_thread(&self, threads: usize) -> usize {
+        (self.warm_pool_size + threads - 1) / threads
+    }
+}
+
+/// Where and how the ingress logs requests. Read once at startup.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// File the log is appended to, relative to the manifest. Defaults to
+    /// stdout.
+    pub path: Option<PathBuf>,
+}
+
+/// TLS termination at the ingress, see [crate::tls]. Read once at startup.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    #[serde(default = "default_tls_port")]
+    pub port: u16,
+    /// Certificates by the hostname they are served for. May be empty when
+    /// [AcmeConfig] provides them. `*.example.com` covers the direct
+    /// subdomains of `example.com` without a certificate of their own, and `*`
+    /// every other hostname, including clients that send no SNI.
+    pub certificates: BTreeMap<String, CertificateConfig>,
+    /// Also serves HTTP/3 over QUIC on UDP `port`, see [crate::http3].
+    #[serde(default)]
+    pub http3: bool,
+}
+
+fn default_tls_port() -> u16 {
+    DEFAULT_TLS_PORT
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    /// PEM certificate chain, leaf first, relative to the manifest.
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1), relative to the manifest.
+    pub key: PathBuf,
+}
+
+/// Automatic certificates for the `domains` of workers, see [crate::acme].
+/// Requires `tls`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// The CA's ACME directory, e.g.
+    /// `https://acme-v02.api.letsencrypt.org/directory`.
+    pub directory_url: String,
+    /// Passed to the CA as the account's contact.
+    pub email: Option<String>,
+    /// Where the account key and certificates are kept, relative to the
+    /// manifest.
+    pub cert_dir: PathBuf,
+    /// An additional PEM root certificate to trust when talking to the CA, e.g.
+    /// of a local test CA.
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// Where spans are exported to, see [crate::trace]. Read once at startup.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// Base URL of an OTLP/HTTP collector, e.g. `http://localhost:4318`. Spans
+    /// are not exported without it, but trace context is still propagated.
+    pub otlp_endpoint: Option<String>,
+    /// Reported as `service.name`. Defaults to [DEFAULT_SERVICE_NAME].
+    pub service_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Path to the worker's main module, relative to the manifest file.
+    pub module: PathBuf,
+    /// Passed through the `env` argument of the worker's fetch handler.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Seconds without requests after which the worker's isolate is shut down.
+    /// `0` keeps it running indefinitely.
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Custom domains routed to the worker in addition to its host slug.
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    DEFAULT_IDLE_TIMEOUT_SECS
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Heap limit of the worker's isolate, between [MIN_HEAP_MB] and
+    /// [MAX_HEAP_MB]. An isolate reaching it is terminated and the request
+    /// fails with a 503.
+    pub max_heap_mb: Option<usize>,
+    /// CPU time a single request may spend running JavaScript, at most
+    /// [MAX_CPU_MS]. An isolate exceeding it is terminated and the request
+    /// fails with a 503. Also bounds evaluating the module and each timer or
+    /// other task run outside of a request, which are otherwise cut off after
+    /// ten seconds.
+    pub cpu_ms: Option<u64>,
+}
+
+impl Manifest {
+    pub fn from_file(path: &Path) -> Result<Self, AnyError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read manifest {}: {e}", path.display()))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("failed to parse manifest {}: {e}", path.display()))
+    }
+
+    pub fn validate_isolates(&self) -> Result<(), AnyError> {
+        if self.isolates.max_running == Some(0) {
+            anyhow::bail!("invalid manifest: isolates.max_running must be greater than zero");
+        }
+        if let Some(timeout) = self.isolates.startup_timeout_ms {
+            if !(1..=MAX_STARTUP_TIMEOUT_MS).contains(&timeout) {
+                anyhow::bail!(
+                    "invalid manifest: isolates.startup_timeout_ms must be between 1 and {MAX_STARTUP_TIMEOUT_MS}"
+                );
+            }
+        }
+        if self.isolates.max_total_heap_mb == Some(0) {
+            anyhow::bail!("invalid manifest: isolates.max_total_heap_mb must be greater than zero");
+        }
+        if self.isolates.threads == Some(0) {
+            anyhow::bail!("invalid manifest: isolates.threads must be greater than zero");
+        }
+        Ok(())
+    }
+
+    pub fn validate_zones(&self) -> Result<(), AnyError> {
+        let errors: Vec<String> = self
+            .zones
+            .iter()
+            .filter_map(|zone| validate_hostname(zone).err())
+            .map(|e| format!("zones: {e}"))
+            .collect();
+        if !errors.is_empty() {
+            return Err(anyhow!("invalid manifest:\n  {}", errors.join("\n  ")));
+        }
+        Ok(())
+    }
+
+    pub fn validate_tls(&self) -> Result<(), AnyError> {
+        match (&self.tls, &self.acme) {
+            (None, Some(_)) => anyhow::bail!("invalid manifest: acme requires tls"),
+            (Some(tls), None) if tls.certificates.is_empty() => {
+                anyhow::bail!("invalid manifest: tls.certificates must not be empty without acme")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Validates every entry and resolves module paths relative to `base_dir`.
+    /// All invalid entries are reported at once, each naming its host slug.
+    pub fn resolve(&self, base_dir: &Path) -> Result<BTreeMap<String, store::Worker>, AnyError> {
+        let mut workers = BTreeMap::new();
+        let mut errors = vec![];
+        let mut domains = BTreeMap::new();
+        for (host_slug, config) in &self.workers {
+            match config.resolve(host_slug, base_dir) {
+                Ok(w) => {
+                    for domain in &w.domains {
+                        if let Some(other) = domains.insert(domain.clone(), host_slug) {
+                            errors.push(format!(
+                                "worker \"{host_slug}\": domain {domain} is also used by worker \"{other}\""
+                            ));
+                        }
+                    }
+                    workers.insert(host_slug.clone(), w);
+                }
+                Err(e) => errors.push(format!("worker \"{host_slug}\": {e}")),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(anyhow!("invalid manifest:\n  {}", errors.join("\n  ")));
+        }
+        Ok(workers)
+    }
+}
+
+impl WorkerConfig {
+    fn resolve(&self, host_slug: &str, base_dir: &Path) -> Result<store::Worker, AnyError> {
+        validate_host_slug(host_slug)?;
+        let path = base_dir.join(&self.module);
+        if !path.is_file() {
+            return Err(anyhow!("module {} does not exist", path.display()));
+        }
+        for key in self.env.keys() {
+            if key.is_empty() {
+                return Err(anyhow!("env var names must not be empty"));
+            }
+        }
+        if let Some(max_heap_mb) = self.limits.max_heap_mb {
+            if !(MIN_HEAP_MB..=MAX_HEAP_MB).contains(&max_heap_mb) {
+                return Err(anyhow!(
+                    "limits.max_heap_mb must be between {MIN_HEAP_MB} and {MAX_HEAP_MB}"
+                ));
+            }
+        }
+        if let Some(cpu_ms) = self.limits.cpu_ms {
+            if !(1..=MAX_CPU_MS).contains(&cpu_ms) {
+                return Err(anyhow!("limits.cpu_ms must be between 1 and {MAX_CPU_MS}"));
+            }
+        }
+        for domain in &self.domains {
+            validate_domain(domain)?;
+        }
+        Ok(store::Worker {
+            module: deno_core::resolve_path(&path.to_string_lossy())?,
+            env_vars: self
+                .env
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            limits: self.limits,
+            idle_timeout: match self.idle_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            domains: self.domains.clone(),
+        })
+    }
+}
+
+/// Host slugs are matched against the first label of the Host header, so they
+/// must be valid DNS labels.
+pub fn validate_host_slug(host_slug: &str) -> Result<(), AnyError> {
+    if host_slug.is_empty() || host_slug.len() > 63 {
+        anyhow::bail!("host slug must be between 1 and 63 characters");
+    }
+    if host_slug.starts_with('-') || host_slug.ends_with('-') {
+        anyhow::bail!("host slug must not start or end with '-'");
+    }
+    if !host_slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        anyhow::bail!("host slug may only contain lowercase letters, digits and '-'");
+    }
+    Ok(())
+}
+
+/// Custom domains are fully qualified lowercase hostnames; they are matched
+/// against the Host header and requested from the CA as is.
+pub fn validate_domain(domain: &str) -> Result<(), AnyError> {
+    if !domain.contains('.') {
+        anyhow::bail!("domain {domain:?} must be a fully qualified hostname");
+    }
+    validate_hostname(domain)
+}
+
+/// Hostnames in the manifest are lowercase, without a trailing dot.
+fn validate_hostname(hostname: &str) -> Result<(), AnyError> {
+    if hostname.len() > 253 {
+        anyhow::bail!("{hostname:?} is longer than 253 characters");
+    }
+    for label in hostname.split('.') {
+        if label.is_empty()
+            || label.len() > 63
+            || label.starts_with('-')
+            || label.ends_with('-')
+            || !label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            anyhow::bail!("{hostname:?} is not a valid lowercase hostname");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_hostnames() {
+        for hostname in [
+            "example.com",
+            "a.b-c.example",
+            "xn--bcher-kva.example",
+            "localhost",
+        ] {
+            assert!(validate_hostname(hostname).is_ok(), "{hostname}");
+        }
+        let long_label = "a".repeat(64);
+        let long_name = ["a".repeat(63).as_str(); 4].join(".");
+        for hostname in [
+            "",
+            "Example.com",
+            "example..com",
+            ".example.com",
+            "example.com.",
+            "-example.com",
+            "example-.com",
+            "exa_mple.com",
+            "*.example.com",
+            long_label.as_str(),
+            long_name.as_str(),
+        ] {
+            assert!(validate_hostname(hostname).is_err(), "{hostname}");
+        }
+    }
+
+    #[test]
+    fn splits_warm_pool_between_threads() {
+        let limits = IsolatesConfig {
+            warm_pool_size: 2,
+            ..Default::default()
+        };
+        assert_eq!(limits.warm_pool_per_thread(1), 2);
+        assert_eq!(limits.warm_pool_per_thread(2), 1);
+        assert_eq!(limits.warm_pool_per_thread(8), 1);
+        assert_eq!(IsolatesConfig::default().warm_pool_per_thread(8), 0);
+    }
+
+    #[test]
+    fn accepts_limits_below_thread_count() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{ "workers": {}, "isolates": { "threads": 4, "max_running": 1, "max_total_heap_mb": 1 } }"#,
+        )
+        .unwrap();
+        assert!(manifest.validate_isolates().is_ok());
+    }
+
+    #[test]
+    fn bounds_startup_timeout() {
+        let mut manifest = Manifest::default();
+        for (timeout, valid) in [
+            (0, false),
+            (1, true),
+            (MAX_STARTUP_TIMEOUT_MS, true),
+            (u64::MAX, false),
+        ] {
+            manifest.isolates.startup_timeout_ms = Some(timeout);
+            assert_eq!(manifest.validate_isolates().is_ok(), valid, "{timeout}");
+        }
+    }
+
+    #[test]
+    fn bounds_worker_limits() {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let resolve = |limits| {
+            let config = WorkerConfig {
+                module: "hello.js".into(),
+                env: BTreeMap::new(),
+                limits,
+                idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+                domains: vec![],
+            };
+            config.resolve("hello", base_dir)
+        };
+        for (max_heap_mb, cpu_ms, valid) in [
+            (MIN_HEAP_MB, 1, true),
+            (MAX_HEAP_MB, MAX_CPU_MS, true),
+            (0, 1, false),
+            (MIN_HEAP_MB - 1, 1, false),
+            (usize::MAX, 1, false),
+            (MIN_HEAP_MB, 0, false),
+            (MIN_HEAP_MB, u64::MAX, false),
+        ] {
+            let limits = Limits {
+                max_heap_mb: Some(max_heap_mb),
+                cpu_ms: Some(cpu_ms),
+            };
+            assert_eq!(resolve(limits).is_ok(), valid, "{limits:?}");
+        }
+    }
+}
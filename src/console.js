@@ -0,0 +1,50 @@
+// Runs in every isolate before the user module. Routes console output through
+// op_console_write, tagged with its level and the id of the request being
+// handled, see src/console.rs. src/serve.js keeps `requests` up to date.
+((core) => {
+  const requests = {
+    // the request whose handler is running synchronously
+    running: null,
+    // request id to { id, traceparent, tracestate }
+    inFlight: new Map(),
+    // Only exact while a handler runs synchronously or a single request is in
+    // flight; otherwise work can't be attributed to a request.
+    current() {
+      if (this.running !== null) {
+        return this.running;
+      }
+      if (this.inFlight.size === 1) {
+        return this.inFlight.values().next().value;
+      }
+      return null;
+    },
+  };
+  Object.defineProperty(globalThis, Symbol.for("openedge.requests"), {
+    value: requests,
+  });
+
+  function write(level, message) {
+    core.ops.op_console_write(level, message, requests.current()?.id ?? null);
+  }
+
+  function format(args) {
+    return args
+      .map((arg) => (typeof arg === "string" ? arg : Deno.inspect(arg)))
+      .join(" ");
+  }
+
+  const levels = {
+    debug: "debug",
+    trace: "debug",
+    log: "info",
+    info: "info",
+    warn: "warn",
+    error: "error",
+  };
+  for (const [method, level] of Object.entries(levels)) {
+    console[method] = (...args) => write(level, format(args));
+  }
+  // everything else the console prints, e.g. console.table
+  core.print = (message, isErr) =>
+    write(isErr ? "error" : "info", message.replace(/\n$/, ""));
+})(Deno.core);
@@ -0,0 +1,168 @@
+//! Console output of workers. Each isolate's console writes through
+//! `op_console_write` (see `console.js`) into the log stream, tagged with the
+//! worker's host slug, and into a ring buffer per worker served by the admin
+//! API. Sandboxed isolates pass their lines on to the ingress, see
+//! [WorkerConsole::forwarding].
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use deno_runtime::deno_core::{op, Extension, OpState};
+use serde::{Deserialize, Serialize};
+
+use crate::access_log::{self, AccessLog};
+
+/// Installs the console capture, run before the user module.
+pub const CONSOLE_JS: &str = include_str!("console.js");
+
+/// Longer messages are truncated.
+const MAX_MESSAGE_BYTES: usize = 16 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsoleLine {
+    pub timestamp: String,
+    pub host_slug: String,
+    pub request_id: Option<String>,
+    pub level: String,
+    pub message: String,
+}
+
+/// The most recent console lines of one worker, kept across its isolates.
+#[derive(Debug)]
+pub struct ConsoleBuffer {
+    lines: RefCell<VecDeque<ConsoleLine>>,
+    capacity: usize,
+}
+
+impl ConsoleBuffer {
+    pub fn new(capacity: usize) -> Rc<Self> {
+        Rc::new(ConsoleBuffer {
+            lines: RefCell::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        })
+    }
+
+    fn push(&self, line: ConsoleLine) {
+        let mut lines = self.lines.borrow_mut();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The buffered lines, oldest first.
+    pub fn lines(&self) -> Vec<ConsoleLine> {
+        self.lines.borrow().iter().cloned().collect()
+    }
+}
+
+/// Where the console of one isolate writes to.
+#[derive(Clone)]
+pub struct WorkerConsole {
+    host_slug: String,
+    output: Output,
+}
+
+#[derive(Clone)]
+enum Output {
+    Log {
+        log: Arc<AccessLog>,
+        buffer: Option<Rc<ConsoleBuffer>>,
+    },
+    /// JSON lines on stdout, read by the ingress.
+    Forward,
+}
+
+impl WorkerConsole {
+    pub fn new(host_slug: String, log: Arc<AccessLog>, buffer: Option<Rc<ConsoleBuffer>>) -> Self {
+        WorkerConsole {
+            host_slug,
+            output: Output::Log { log, buffer },
+        }
+    }
+
+    /// A console writing each line to stdout as JSON, for the ingress to pass
+    /// to [WorkerConsole::record]. Used by sandboxed isolates.
+    pub fn forwarding(host_slug: String) -> Self {
+        WorkerConsole {
+            host_slug,
+            output: Output::Forward,
+        }
+    }
+
+    pub fn host_slug(&self) -> &str {
+        &self.host_slug
+    }
+
+    /// Records a line written by a sandboxed isolate of this worker. The line
+    /// is attributed to this worker whatever host slug it names.
+    pub fn record(&self, mut line: ConsoleLine) {
+        line.host_slug = self.host_slug.clone();
+        truncate(&mut line.message);
+        self.output(line);
+    }
+
+    fn write(&self, level: String, mut message: String, request_id: Option<String>) {
+        truncate(&mut message);
+        self.output(ConsoleLine {
+            timestamp: access_log::rfc3339(SystemTime::now()),
+            host_slug: self.host_slug.clone(),
+            request_id,
+            level,
+            message,
+        });
+    }
+
+    fn output(&self, line: ConsoleLine) {
+        match &self.output {
+            Output::Log { log, buffer } => {
+                log.record_console(&line);
+                if let Some(buffer) = buffer {
+                    buffer.push(line);
+                }
+            }
+            Output::Forward => {
+                if let Ok(line) = serde_json::to_string(&line) {
+                    println!("{line}");
+                }
+            }
+        }
+    }
+}
+
+fn truncate(message: &mut String) {
+    if message.len() > MAX_MESSAGE_BYTES {
+        let mut end = MAX_MESSAGE_BYTES;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+}
+
+pub fn init() -> Extension {
+    Extension::builder()
+        .ops(vec![op_console_write::decl()])
+        .build()
+}
+
+/// Sends console output of the isolate owning `state` to `console`.
+pub fn attach(state: &mut OpState, console: WorkerConsole) {
+    state.put(console);
+}
+
+#[op]
+fn op_console_write(
+    state: &mut OpState,
+    level: String,
+    message: String,
+    request_id: Option<String>,
+) {
+    match state.try_borrow::<WorkerConsole>() {
+        Some(console) => console.write(level, message, request_id),
+        // not yet running a worker, e.g. while bootstrapping
+        None => println!("{message}"),
+    }
+}
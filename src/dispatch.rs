@@ -0,0 +1,221 @@
+//! In-process request dispatch. The ingress hands requests to an isolate through
+//! a channel, and the isolate's wrapper module pulls them off with
+//! `op_next_request` and streams its response back through the remaining ops.
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use deno_runtime::deno_core::anyhow::anyhow;
+use deno_runtime::deno_core::error::AnyError;
+use deno_runtime::deno_core::{
+    op, AsyncRefCell, ByteString, Extension, OpState, RcRef, Resource, ResourceId, ZeroCopyBuf,
+};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::HOST;
+use hyper::{Body, Method, Request, Response};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::worker::IsolateStats;
+
+pub struct IsolateRequest {
+    pub req: Request<Body>,
+    pub respond: oneshot::Sender<Response<Body>>,
+}
+
+pub type RequestSender = mpsc::UnboundedSender<IsolateRequest>;
+
+/// Queues `req` on an isolate's request channel and waits for the response head.
+pub async fn dispatch(
+    requests: &RequestSender,
+    req: Request<Body>,
+) -> Result<Response<Body>, AnyError> {
+    let (respond, response) = oneshot::channel();
+    requests
+        .send(IsolateRequest { req, respond })
+        .map_err(|_| anyhow!("isolate is no longer accepting requests"))?;
+    response
+        .await
+        .map_err(|_| anyhow!("isolate exited before responding"))
+}
+
+pub fn init() -> Extension {
+    Extension::builder()
+        .ops(vec![
+            op_next_request::decl(),
+            op_request_body_read::decl(),
+            op_respond::decl(),
+            op_response_body_write::decl(),
+        ])
+        .build()
+}
+
+/// Makes `requests` available to the dispatch ops of the isolate owning `state`.
+/// In-flight requests are tracked in `stats`.
+pub fn attach(
+    state: &mut OpState,
+    requests: mpsc::UnboundedReceiver<IsolateRequest>,
+    stats: Rc<IsolateStats>,
+) {
+    state.put(Rc::new(RequestStream {
+        rx: AsyncRefCell::new(requests),
+    }));
+    state.put(stats);
+}
+
+struct RequestStream {
+    rx: AsyncRefCell<mpsc::UnboundedReceiver<IsolateRequest>>,
+}
+
+struct InFlightRequest {
+    body: AsyncRefCell<Body>,
+    respond: RefCell<Option<oneshot::Sender<Response<Body>>>>,
+    response_body: AsyncRefCell<Option<hyper::body::Sender>>,
+    stats: Rc<IsolateStats>,
+    stats_id: u64,
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.stats.request_finished(self.stats_id);
+    }
+}
+
+impl Resource for InFlightRequest {
+    fn name(&self) -> Cow<str> {
+        "inFlightRequest".into()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NextRequest {
+    rid: ResourceId,
+    method: String,
+    url: String,
+    headers: Vec<(ByteString, ByteString)>,
+    has_body: bool,
+}
+
+/// Resolves to the next request routed to this isolate, or `null` once the
+/// ingress has stopped routing to it.
+#[op]
+async fn op_next_request(state: Rc<RefCell<OpState>>) -> Result<Option<NextRequest>, AnyError> {
+    let stream = state.borrow().borrow::<Rc<RequestStream>>().clone();
+    let next = RcRef::map(&stream, |s| &s.rx)
+        .borrow_mut()
+        .await
+        .recv()
+        .await;
+    let IsolateRequest { req, respond } = match next {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+
+    let (parts, body) = req.into_parts();
+    // `:authority` takes precedence over Host, see RFC 9113 section 8.3.1
+    let host = parts
+        .uri
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| parts.headers.get(HOST).and_then(|h| h.to_str().ok()))
+        .unwrap_or("localhost");
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let url = format!("http://{host}{path}");
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(k, v)| (k.as_str().into(), v.as_bytes().into()))
+        .collect();
+    let has_body = !matches!(parts.method, Method::GET | Method::HEAD) && !body.is_end_stream();
+
+    let mut state = state.borrow_mut();
+    let stats = state.borrow::<Rc<IsolateStats>>().clone();
+    let rid = state.resource_table.add(InFlightRequest {
+        body: AsyncRefCell::new(body),
+        respond: RefCell::new(Some(respond)),
+        response_body: AsyncRefCell::new(None),
+        stats_id: stats.request_started(),
+        stats,
+    });
+    Ok(Some(NextRequest {
+        rid,
+        method: parts.method.to_string(),
+        url,
+        headers,
+        has_body,
+    }))
+}
+
+/// Resolves to the next chunk of the request body, or `null` at its end.
+#[op]
+async fn op_request_body_read(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+) -> Result<Option<ZeroCopyBuf>, AnyError> {
+    let request = state.borrow().resource_table.get::<InFlightRequest>(rid)?;
+    let mut body = RcRef::map(&request, |r| &r.body).borrow_mut().await;
+    match body.data().await {
+        Some(chunk) => Ok(Some(chunk?.to_vec().into())),
+        None => Ok(None),
+    }
+}
+
+/// Sends the response head. When `has_body` is set, the body follows through
+/// [op_response_body_write] and ends when the request resource is closed.
+#[op]
+fn op_respond(
+    state: &mut OpState,
+    rid: ResourceId,
+    status: u16,
+    headers: Vec<(ByteString, ByteString)>,
+    has_body: bool,
+) -> Result<(), AnyError> {
+    let request = state.resource_table.get::<InFlightRequest>(rid)?;
+    let respond = request
+        .respond
+        .borrow_mut()
+        .take()
+        .ok_or_else(|| anyhow!("response already sent"))?;
+
+    let mut builder = Response::builder().status(status);
+    for (key, value) in headers {
+        builder = builder.header(key.as_slice(), value.as_slice());
+    }
+    let body = if has_body {
+        let (sender, body) = Body::channel();
+        // the request is still in flight, so nothing else can hold this borrow
+        *RcRef::map(&request, |r| &r.response_body)
+            .try_borrow_mut()
+            .ok_or_else(|| anyhow!("response body busy"))? = Some(sender);
+        body
+    } else {
+        state.resource_table.close(rid)?;
+        Body::empty()
+    };
+    // the client may have gone away, in which case there is no one to respond to
+    let _ = respond.send(builder.body(body)?);
+    Ok(())
+}
+
+#[op]
+async fn op_response_body_write(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    chunk: ZeroCopyBuf,
+) -> Result<(), AnyError> {
+    let request = state.borrow().resource_table.get::<InFlightRequest>(rid)?;
+    let mut response_body = RcRef::map(&request, |r| &r.response_body)
+        .borrow_mut()
+        .await;
+    let sender =
+        Option::as_mut(&mut *response_body).ok_or_else(|| anyhow!("response has no body"))?;
+    sender
+        .send_data(Bytes::copy_from_slice(&chunk))
+        .await
+        .map_err(|_| anyhow!("client disconnected"))
+}
@@ -0,0 +1,23 @@
+// Runs in every isolate after src/console.js. Passes the trace context of the
+// request being handled on to outbound requests, see src/trace.rs. Calls that
+// can't be attributed to a request, see `requests.current()`, are sent as is.
+((requests) => {
+  const fetch = globalThis.fetch;
+  globalThis.fetch = function (input, init) {
+    const request = requests.current();
+    if (!request?.traceparent) {
+      return fetch(input, init);
+    }
+    const req = new Request(input, init);
+    // a worker continuing a trace of its own knows better
+    if (!req.headers.has("traceparent")) {
+      req.headers.set("traceparent", request.traceparent);
+      if (request.tracestate) {
+        req.headers.set("tracestate", request.tracestate);
+      } else {
+        req.headers.delete("tracestate");
+      }
+    }
+    return fetch(req);
+  };
+})(globalThis[Symbol.for("openedge.requests")]);
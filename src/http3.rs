@@ -0,0 +1,117 @@
+//! HTTP/3 at the ingress. A QUIC endpoint on the UDP port of the TLS listener
+//! serves the same certificates and routes requests like the TCP listeners;
+//! those advertise it to clients with `Alt-Svc`.
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use deno_runtime::deno_core::error::AnyError;
+use h3::server::RequestStream;
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response};
+use tokio_rustls::rustls::{version::TLS13, ServerConfig};
+
+use crate::tls::SniResolver;
+use crate::Ingress;
+
+/// How long clients may remember the `Alt-Svc` advertisement, in seconds.
+const ALT_SVC_MAX_AGE: u32 = 86400;
+
+/// The `Alt-Svc` value advertising HTTP/3 on UDP `port`.
+pub fn alt_svc(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{port}\"; ma={ALT_SVC_MAX_AGE}")).unwrap()
+}
+
+/// Creates a QUIC endpoint on `addr` serving the certificates of `resolver`.
+pub fn bind(addr: SocketAddr, resolver: Arc<SniResolver>) -> Result<quinn::Endpoint, AnyError> {
+    // QUIC requires TLS 1.3
+    let mut crypto = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&TLS13])?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Ok(quinn::Endpoint::server(server_config, addr)?)
+}
+
+/// Accepts QUIC connections on `endpoint` and serves their requests like the
+/// TCP ingress, passing on the SNI hostname for routing.
+pub async fn serve(endpoint: quinn::Endpoint, ingress: Ingress) -> Result<(), AnyError> {
+    while let Some(connecting) = endpoint.accept().await {
+        let ingress = ingress.clone();
+        tokio::task::spawn_local(async move {
+            let client = connecting.remote_address();
+            if let Err(e) = serve_connection(connecting, ingress).await {
+                println!("Error serving HTTP/3 connection from {client}: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_connection(connecting: quinn::Connecting, ingress: Ingress) -> Result<(), AnyError> {
+    let client_ip = connecting.remote_address().ip();
+    let connection = connecting.await?;
+    let sni = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.server_name);
+    let mut connection =
+        h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await?;
+    // every request stream is served concurrently
+    while let Some((req, stream)) = connection.accept().await? {
+        let ingress = ingress.clone();
+        let sni = sni.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = serve_request(req, stream, ingress, client_ip, sni).await {
+                println!("Error serving HTTP/3 request: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_request<S>(
+    req: Request<()>,
+    stream: RequestStream<S, Bytes>,
+    ingress: Ingress,
+    client_ip: IpAddr,
+    sni: Option<String>,
+) -> Result<(), AnyError>
+where
+    S: h3::quic::BidiStream<Bytes> + 'static,
+    S::RecvStream: 'static,
+{
+    let (mut send, mut recv) = stream.split();
+    let (mut body_tx, body) = Body::channel();
+    tokio::task::spawn_local(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    let chunk = chunk.copy_to_bytes(chunk.remaining());
+                    if body_tx.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(_) => return body_tx.abort(),
+            }
+        }
+    });
+
+    let (parts, ()) = req.into_parts();
+    let resp = match crate::handle(ingress, client_ip, sni, Request::from_parts(parts, body)).await
+    {
+        Ok(resp) => resp,
+        Err(infallible) => match infallible {},
+    };
+    let (parts, mut body) = resp.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -4,8 +4,10 @@ use deno_runtime::deno_core;
 use deno_runtime::deno_core::error::generic_error;
 use deno_runtime::deno_core::futures::FutureExt;
 use deno_runtime::deno_core::ModuleLoader;
+use deno_runtime::deno_core::ModuleSource;
 use deno_runtime::deno_core::ModuleSourceFuture;
 use deno_runtime::deno_core::ModuleSpecifier;
+use deno_runtime::deno_core::ModuleType;
 use std::pin::Pin;
 
 pub struct UserModuleWrapper {
@@ -15,48 +17,71 @@ pub struct UserModuleWrapper {
 
 const WRAPPER_MODULE_SPEC: &str = "file:///wrapper.js";
 
+/// The request loop run by every isolate, see [crate::dispatch].
+const SERVE_LOOP: &str = include_str!("serve.js");
+
 pub fn new_wrapper<'a, E: IntoIterator<Item = &'a (&'a str, &'a str)>>(
     user_module: &ModuleSpecifier,
     env_vars: E,
-    port: u16,
 ) -> UserModuleWrapper {
     let code = format!(
-        "import worker from \"{}\"; 
-Deno.serve(async (req) => {{
-    try {{
-        const resp = await worker.fetch(req, {{{}}})
-        return resp
-    }} catch(e) {{
-        console.log(e)
-        return new Response(\"internal server error\\n\", {{ status: 500 }})
-    }}
-}}, {{
-    hostname: \"0.0.0.0\",
-    port: \"{}\",
-}})
-",
-        user_module.as_str(),
+        "import worker from {};
+const env = {{{}}};
+{}",
+        serde_json::Value::from(user_module.as_str()),
         to_js_keyvalues(env_vars),
-        port
+        SERVE_LOOP
     );
-    let spec = deno_core::resolve_url(WRAPPER_MODULE_SPEC).unwrap();
+    UserModuleWrapper {
+        code,
+        spec: wrapper_specifier(),
+    }
+}
 
-    UserModuleWrapper { code, spec }
+pub fn wrapper_specifier() -> ModuleSpecifier {
+    deno_core::resolve_url(WRAPPER_MODULE_SPEC).unwrap()
 }
 
 fn to_js_keyvalues<'a, T: IntoIterator<Item = &'a (&'a str, &'a str)>>(key_pairs: T) -> String {
     key_pairs
         .into_iter()
-        .map(|(key, value)| format!("\"{key}\": \"{value}\""))
+        .map(|(key, value)| {
+            format!(
+                "{}: {}",
+                serde_json::Value::from(*key),
+                serde_json::Value::from(*value)
+            )
+        })
         .collect::<Vec<String>>()
         .join(",\n")
 }
 
-pub struct OnlyLoadWrapperImports(FsModuleLoader);
+pub struct OnlyLoadWrapperImports {
+    fs: FsModuleLoader,
+    /// Source of the user module, when it must not be read from disk.
+    preloaded: Option<(ModuleSpecifier, String)>,
+}
+
+impl Default for OnlyLoadWrapperImports {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl OnlyLoadWrapperImports {
     pub fn new() -> Self {
-        OnlyLoadWrapperImports(FsModuleLoader)
+        OnlyLoadWrapperImports {
+            fs: FsModuleLoader,
+            preloaded: None,
+        }
+    }
+
+    /// Serves `source` as `module` and never touches the filesystem.
+    pub fn preloaded(module: ModuleSpecifier, source: String) -> Self {
+        OnlyLoadWrapperImports {
+            fs: FsModuleLoader,
+            preloaded: Some((module, source)),
+        }
     }
 }
 
@@ -68,7 +93,7 @@ impl ModuleLoader for OnlyLoadWrapperImports {
         is_main: bool,
     ) -> Result<ModuleSpecifier, anyhow::Error> {
         if (is_main && specifier == WRAPPER_MODULE_SPEC) || referrer == WRAPPER_MODULE_SPEC {
-            self.0.resolve(specifier, referrer, is_main)
+            self.fs.resolve(specifier, referrer, is_main)
         } else {
             Err(generic_error("Module loading is not supported"))
         }
@@ -84,8 +109,21 @@ impl ModuleLoader for OnlyLoadWrapperImports {
             async { Err(generic_error("Dynamic import() statements not supported")) }.boxed_local()
         } else if module_specifier.scheme() != "file" {
             async { Err(generic_error("Main module must be a file path")) }.boxed_local()
+        } else if let Some((module, source)) = &self.preloaded {
+            let result = if module == module_specifier {
+                Ok(ModuleSource {
+                    code: source.as_bytes().into(),
+                    module_type: ModuleType::JavaScript,
+                    module_url_specified: module.to_string(),
+                    module_url_found: module.to_string(),
+                })
+            } else {
+                Err(generic_error("Module loading is not supported"))
+            };
+            async { result }.boxed_local()
         } else {
-            self.0.load(module_specifier, maybe_referrer, is_dyn_import)
+            self.fs
+                .load(module_specifier, maybe_referrer, is_dyn_import)
         }
     }
 }
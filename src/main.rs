@@ -1,103 +1,691 @@
-use deno_core::anyhow;
+use access_log::{AccessLog, LoggedBody};
 use deno_core::error::AnyError;
+use deno_runtime::deno_core;
 use deno_runtime::deno_core::futures::TryFutureExt;
-use deno_runtime::deno_core::{self, ModuleSpecifier};
+use hyper::header::{HeaderValue, ALT_SVC};
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
-use hyper::{Body, Request, Response};
-use std::cell::RefCell;
-use std::collections::{BTreeSet, HashMap};
+use hyper::{Body, Request, Response, Version};
+use metrics::{IsolateError, IsolateSample, Metrics};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{convert::Infallible, net::SocketAddr};
+use threads::IsolateThreads;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use worker::run_usercode;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use trace::{SpanKind, TraceContext, Tracer, TRACEPARENT_HEADER, TRACESTATE_HEADER};
+use worker::{run_usercode, ExitReason, IsolateStats, Startup, WarmPool};
 
+pub mod access_log;
+pub mod acme;
+pub mod admin;
+pub mod config;
+pub mod console;
+pub mod dispatch;
+pub mod http3;
 pub mod loader;
+pub mod metrics;
+pub mod reload;
 pub mod router;
 pub mod runtime;
+pub mod sandbox;
 pub mod store;
+pub mod supervisor;
+pub mod threads;
+pub mod tls;
+pub mod trace;
+pub mod watchdog;
 pub mod worker;
 
+/// What the ingress listeners share.
+#[derive(Clone)]
+pub struct Ingress {
+    pub threads: IsolateThreads,
+    pub access_log: Arc<AccessLog>,
+    /// Pending ACME HTTP-01 challenges, answered before routing.
+    pub challenges: acme::Challenges,
+    /// Set on responses of the listener, advertising HTTP/3.
+    pub alt_svc: Option<HeaderValue>,
+}
+
 async fn handle(
+    ingress: Ingress,
+    client_ip: IpAddr,
+    sni: Option<String>,
+    mut req: Request<Body>,
+) -> Result<Response<LoggedBody>, Infallible> {
+    let Ingress {
+        threads,
+        access_log,
+        challenges,
+        alt_svc,
+    } = ingress;
+    let started = std::time::Instant::now();
+    // overrides any id sent by the client so ids stay unique
+    let request_id = uuid::Uuid::new_v4().to_string();
+    req.headers_mut().insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(&request_id).unwrap(),
+    );
+    let parent = req
+        .headers()
+        .get(TRACEPARENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(TraceContext::parse);
+    // tracestate belongs to the traceparent it came with
+    if parent.is_none() {
+        req.headers_mut().remove(TRACESTATE_HEADER);
+    }
+    let mut span = threads
+        .tracer
+        .start("request", SpanKind::Server, parent.as_ref());
+    span.set_attribute("http.method", req.method().as_str());
+    span.set_attribute("http.target", req.uri().path());
+    span.set_attribute("http.flavor", http_flavor(req.version()));
+    span.set_attribute("openedge.request_id", request_id.clone());
+    let trace = *span.context();
+    let mut entry = access_log::Entry::new(
+        request_id,
+        req.method().to_string(),
+        req.uri().path().to_string(),
+        Some(client_ip),
+    );
+    let routed = if let Some(key_authorization) = challenges.respond(req.uri().path()) {
+        Routed::without_isolate(Response::builder().body(key_authorization.into()).unwrap())
+    } else {
+        match router::host_slug(&threads.store, &req, sni.as_deref()) {
+            Ok(host_slug) => {
+                entry.host_slug = Some(host_slug.clone());
+                span.set_attribute("openedge.host_slug", host_slug.clone());
+                threads
+                    .run_for(&host_slug.clone(), move |state| {
+                        handle_on_thread(state, host_slug, req, trace)
+                    })
+                    .await
+                    .unwrap_or_else(|_| Routed::without_isolate(routing_failed()))
+            }
+            Err(e) if e.is::<router::NotFound>() => Routed::without_isolate(not_found(&e)),
+            Err(e) => Routed::without_isolate(
+                Response::builder()
+                    .status(400)
+                    .body(format!("{e}\n").into())
+                    .unwrap(),
+            ),
+        }
+    };
+    entry.status = routed.response.status().as_u16();
+    entry.isolate_id = routed.isolate_id;
+    entry.cold_start = routed.cold_start;
+    // unknown host slugs share a label so they can't grow the metrics unbounded
+    let metrics_slug = entry
+        .host_slug
+        .as_deref()
+        .filter(|host_slug| threads.store.contains(host_slug));
+    threads
+        .metrics
+        .request_served(metrics_slug, entry.status, started.elapsed());
+    span.set_attribute("http.status_code", entry.status);
+    if entry.status >= 500 {
+        span.set_error();
+    }
+    span.end();
+    let mut response = routed.response;
+    if let Some(alt_svc) = alt_svc {
+        response.headers_mut().insert(ALT_SVC, alt_svc);
+    }
+    Ok(response.map(|body| LoggedBody::new(body, entry, started, access_log)))
+}
+
+/// Set on every request passed to a worker, identifying it in the access log
+/// and in the worker's console output.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// A response and how its request was routed.
+struct Routed {
+    response: Response<Body>,
+    isolate_id: Option<u64>,
+    /// Whether the request had to wait for its isolate to start.
+    cold_start: bool,
+}
+
+impl Routed {
+    fn without_isolate(response: Response<Body>) -> Self {
+        Routed {
+            response,
+            isolate_id: None,
+            cold_start: false,
+        }
+    }
+}
+
+/// Routes `req` to the isolate serving `host_slug`, on the thread the slug is
+/// pinned to. Spans are recorded as children of `trace`, and the isolate's span
+/// is passed on in the request's `traceparent`.
+async fn handle_on_thread(
     state: IsolateManager,
-    addr: SocketAddr,
-    req: Request<Body>,
-) -> Result<Response<Body>, Infallible> {
-    match router::resolve_to_proxy(state, &req).await {
-        Ok(proxy_url) => {
-            match hyper_reverse_proxy::call(addr.ip(), proxy_url.as_str(), req).await {
-                Ok(resp) => Ok(resp),
-                Err(_e) => Ok(Response::builder().status(500).body(Body::empty()).unwrap()),
+    host_slug: String,
+    mut req: Request<Body>,
+    trace: TraceContext,
+) -> Routed {
+    let cold_start = state
+        .get_running_isolate(&host_slug)
+        .map_or(true, |w| !*w.ready.borrow());
+    let metrics = state.metrics.clone();
+    let tracer = state.tracer.clone();
+    let mut route_span = tracer.start("route", SpanKind::Internal, Some(&trace));
+    let resolved = router::resolve_to_isolate(state, &host_slug, route_span.context()).await;
+    if resolved.is_err() {
+        route_span.set_error();
+    }
+    route_span.end();
+    let worker = match resolved {
+        Ok(worker) => worker,
+        Err(e) if e.is::<router::ServiceUnavailable>() => {
+            metrics.isolate_error(&host_slug, IsolateError::Unavailable);
+            return Routed::without_isolate(
+                Response::builder()
+                    .status(503)
+                    .body(format!("{e}\n").into())
+                    .unwrap(),
+            );
+        }
+        Err(e) if e.is::<router::NotFound>() => return Routed::without_isolate(not_found(&e)),
+        Err(_e) => return Routed::without_isolate(routing_failed()),
+    };
+    let mut isolate_span = tracer.start("isolate", SpanKind::Internal, Some(&trace));
+    isolate_span.set_attribute("openedge.isolate_id", worker.id);
+    req.headers_mut().insert(
+        TRACEPARENT_HEADER,
+        HeaderValue::from_str(&isolate_span.context().to_traceparent()).unwrap(),
+    );
+    let response = match dispatch::dispatch(&worker.requests, req).await {
+        Ok(resp) => resp,
+        Err(_e) => {
+            isolate_span.set_error();
+            let reason = worker.stats.exit_reason.get();
+            metrics.isolate_error(
+                &host_slug,
+                match reason {
+                    Some(ExitReason::CpuLimit) => IsolateError::CpuLimit,
+                    Some(ExitReason::HeapLimit) => IsolateError::HeapLimit,
+                    Some(ExitReason::StartupTimeout) => IsolateError::Unavailable,
+                    None => IsolateError::NoResponse,
+                },
+            );
+            match reason {
+                Some(reason) => Response::builder()
+                    .status(503)
+                    .body(format!("{}\n", reason.message()).into())
+                    .unwrap(),
+                None => Response::builder().status(502).body(Body::empty()).unwrap(),
             }
         }
-        Err(_e) => Ok(Response::builder()
-            .status(500)
-            .body("failed routing to isolate".into())
-            .unwrap()),
+    };
+    isolate_span.end();
+    Routed {
+        response,
+        isolate_id: Some(worker.id),
+        cold_start,
     }
 }
 
-async fn startup_new_worker(
+fn not_found(e: &AnyError) -> Response<Body> {
+    Response::builder()
+        .status(404)
+        .body(format!("{e}\n").into())
+        .unwrap()
+}
+
+fn routing_failed() -> Response<Body> {
+    Response::builder()
+        .status(500)
+        .body("failed routing to isolate".into())
+        .unwrap()
+}
+
+/// Spawns an isolate running `usercode`. Requests may be dispatched to it right
+/// away; they are queued until it is ready, see
+/// [RunningIsolateMetadata::wait_until_ready].
+fn startup_new_worker(
     state: &mut IsolateManager,
     host_slug: String,
-    main_module: ModuleSpecifier,
-) -> Result<RunningIsolateMetadata, AnyError> {
-    let port = state
-        .take_available_port()
-        .ok_or_else(|| anyhow::anyhow!("ran out of ports!"))?;
+    usercode: store::Worker,
+) -> RunningIsolateMetadata {
+    let id = state.next_isolate_id.fetch_add(1, Ordering::Relaxed);
+    let (requests, requests_rx) = mpsc::unbounded_channel();
+    let (ready, ready_rx) = watch::channel(false);
 
+    let idle_timeout = usercode.idle_timeout;
+    let stats = Rc::new(IsolateStats::default());
+    let isolate_stats = stats.clone();
+    // pooled runtimes are created with V8's default heap limit
+    let prewarmed = match usercode.limits.max_heap_mb {
+        Some(_) => None,
+        None if state.limits.sandbox => None,
+        None => state.warm_pool.take(),
+    };
+    let sandboxed = state.limits.sandbox;
+    let startup = Startup {
+        ready,
+        timeout: state.limits.startup_timeout(),
+    };
+    let watchdog = state.watchdog.clone();
+    let console = state.console_for(&host_slug);
+    let thread = state.thread;
     let state = state.clone();
-    tokio::task::spawn_local(async move {
-        match run_usercode(main_module, port).await {
-            Ok(()) => {}
-            Err(e) => {
-                println!("user code failed: {e}");
-                state.running.borrow_mut().remove(&host_slug);
-            }
-        }
+    let started_at = Instant::now();
+    let task = tokio::task::spawn_local(async move {
+        let result = if sandboxed {
+            sandbox::run_sandboxed(
+                usercode,
+                requests_rx,
+                startup,
+                isolate_stats.clone(),
+                console,
+            )
+            .await
+        } else {
+            run_usercode(
+                usercode,
+                prewarmed,
+                requests_rx,
+                startup,
+                isolate_stats.clone(),
+                watchdog,
+                console,
+            )
+            .await
+        };
+        state.isolate_finished(id);
+        supervisor::isolate_exited(&state, &host_slug, id, started_at, result, &isolate_stats);
     });
 
-    Ok(RunningIsolateMetadata { port })
+    RunningIsolateMetadata {
+        id,
+        thread,
+        requests,
+        ready: ready_rx,
+        task: Rc::new(task),
+        last_request: Rc::new(Cell::new(Instant::now())),
+        idle_timeout,
+        stats,
+    }
 }
 
+/// How long a replaced or removed isolate keeps serving in-flight requests
+/// before it is torn down.
+const DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// How often running isolates are checked against their idle timeout.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone, Debug)]
 pub struct RunningIsolateMetadata {
-    port: u16,
+    id: u64,
+    /// Index of the isolate thread running it.
+    thread: usize,
+    requests: dispatch::RequestSender,
+    /// Set once the user module has been evaluated. The sender is dropped if
+    /// the isolate exits before that.
+    ready: watch::Receiver<bool>,
+    task: Rc<tokio::task::JoinHandle<()>>,
+    last_request: Rc<Cell<Instant>>,
+    idle_timeout: Option<Duration>,
+    stats: Rc<IsolateStats>,
+}
+
+/// What is reported about a running isolate outside of its thread.
+#[derive(Clone, Debug, Serialize)]
+pub struct IsolateInfo {
+    pub id: u64,
+    pub thread: usize,
+}
+
+impl RunningIsolateMetadata {
+    pub fn info(&self) -> IsolateInfo {
+        IsolateInfo {
+            id: self.id,
+            thread: self.thread,
+        }
+    }
+
+    async fn wait_until_ready(&self) -> Result<(), AnyError> {
+        let mut ready = self.ready.clone();
+        while !*ready.borrow() {
+            ready.changed().await.map_err(|_| {
+                router::ServiceUnavailable("worker exited before becoming ready".to_string())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn touch(&self) {
+        self.last_request.set(Instant::now());
+    }
+
+    /// Whether the isolate has gone without requests for its idle timeout.
+    /// Requests still being answered, such as long streamed responses, keep it
+    /// busy however long ago they arrived.
+    fn is_idle(&self, now: Instant) -> bool {
+        if self.stats.in_flight() > 0 {
+            return false;
+        }
+        match self.idle_timeout {
+            Some(timeout) => now.duration_since(self.last_request.get()) >= timeout,
+            None => false,
+        }
+    }
+}
+
+/// An isolate that no longer receives requests but has not exited yet. It
+/// still counts against the limits of [config::IsolatesConfig].
+#[derive(Debug)]
+struct DrainingIsolate {
+    task: Rc<tokio::task::JoinHandle<()>>,
+    stats: Rc<IsolateStats>,
 }
 
 // TODO: fix this entire abstraction
+/// The isolates of one isolate thread. See [threads].
 #[derive(Clone, Debug)]
 pub struct IsolateManager {
     running: Rc<RefCell<HashMap<String, RunningIsolateMetadata>>>,
-    available_ports: Rc<RefCell<BTreeSet<u16>>>,
+    /// Drained isolates by id, until they exit. See [IsolateManager::drain_isolate].
+    draining: Rc<RefCell<HashMap<u64, DrainingIsolate>>>,
+    next_isolate_id: Arc<AtomicU64>,
+    thread: usize,
+    /// All isolate threads, including this one. See [threads::Usage].
+    threads: IsolateThreads,
     store: store::Store,
+    limits: config::IsolatesConfig,
+    warm_pool: Rc<WarmPool>,
+    watchdog: watchdog::Watchdog,
+    crashes: supervisor::CrashHistory,
+    access_log: Arc<AccessLog>,
+    metrics: Arc<Metrics>,
+    tracer: Tracer,
+    /// Console lines of the workers on this thread, kept across their
+    /// isolates. See [console].
+    consoles: Rc<RefCell<HashMap<String, Rc<console::ConsoleBuffer>>>>,
 }
 
 impl IsolateManager {
+    fn new(thread: threads::ThreadState) -> Self {
+        IsolateManager {
+            running: Rc::new(RefCell::new(HashMap::new())),
+            draining: Rc::new(RefCell::new(HashMap::new())),
+            next_isolate_id: thread.next_isolate_id,
+            thread: thread.index,
+            store: thread.threads.store.clone(),
+            warm_pool: WarmPool::new(if thread.limits.sandbox {
+                0
+            } else {
+                thread
+                    .limits
+                    .warm_pool_per_thread(thread.threads.thread_count())
+            }),
+            limits: thread.limits,
+            watchdog: thread.watchdog,
+            crashes: supervisor::CrashHistory::default(),
+            access_log: thread.access_log,
+            metrics: thread.threads.metrics.clone(),
+            tracer: thread.threads.tracer.clone(),
+            threads: thread.threads,
+            consoles: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Where the console of a new isolate for `hostname` writes to.
+    fn console_for(&self, hostname: &str) -> console::WorkerConsole {
+        let capacity = self.limits.console_buffer_lines();
+        let buffer = (capacity > 0).then(|| {
+            self.consoles
+                .borrow_mut()
+                .entry(hostname.to_string())
+                .or_insert_with(|| console::ConsoleBuffer::new(capacity))
+                .clone()
+        });
+        console::WorkerConsole::new(hostname.to_string(), self.access_log.clone(), buffer)
+    }
+
+    /// The running isolates of this thread, for [metrics].
+    fn isolate_samples(&self) -> Vec<IsolateSample> {
+        self.running
+            .borrow()
+            .iter()
+            .map(|(hostname, w)| IsolateSample {
+                host_slug: hostname.clone(),
+                id: w.id,
+                thread: w.thread,
+                heap_used: w.stats.heap_used.get(),
+            })
+            .collect()
+    }
+
+    /// The buffered console lines of `hostname`, oldest first.
+    fn console_lines(&self, hostname: &str) -> Vec<console::ConsoleLine> {
+        self.consoles
+            .borrow()
+            .get(hostname)
+            .map_or_else(Vec::new, |buffer| buffer.lines())
+    }
+
     fn register_new_isolate(&self, hostname: &str, worker: RunningIsolateMetadata) {
         self.running
             .borrow_mut()
             .insert(hostname.to_string(), worker);
+        self.publish_usage();
+    }
+
+    fn get_running_isolate(&self, hostname: &str) -> Option<RunningIsolateMetadata> {
+        self.running.borrow().get(hostname).cloned()
     }
-    fn take_available_port(&mut self) -> Option<u16> {
-        let next_port = match self.available_ports.borrow().iter().next().cloned() {
-            Some(p) => p,
-            None => return None,
+
+    fn remove_isolate_if_id(&self, hostname: &str, id: u64) {
+        let mut running = self.running.borrow_mut();
+        if running.get(hostname).map(|w| w.id) == Some(id) {
+            running.remove(hostname);
+        }
+        drop(running);
+        self.publish_usage();
+    }
+
+    /// Stops routing to the isolate serving `hostname`, if any. The isolate exits
+    /// once its in-flight requests complete, or is torn down after
+    /// [DRAIN_GRACE_PERIOD]. The next request for `hostname` cold starts a new
+    /// isolate.
+    fn drain_isolate(&self, hostname: &str) {
+        let worker = match self.running.borrow_mut().remove(hostname) {
+            Some(w) => w,
+            None => return,
         };
-        self.available_ports.borrow_mut().take(&next_port);
-        Some(next_port)
+        let id = worker.id;
+        self.draining.borrow_mut().insert(
+            id,
+            DrainingIsolate {
+                task: worker.task.clone(),
+                stats: worker.stats.clone(),
+            },
+        );
+        // closes the request channel once in-flight dispatches release their handles
+        drop(worker);
+        self.publish_usage();
+        let state = self.clone();
+        tokio::task::spawn_local(async move {
+            tokio::time::sleep(DRAIN_GRACE_PERIOD).await;
+            state.abort_draining_isolate(id);
+        });
     }
 
-    fn get_running_isolate_port(&self, hostname: &str) -> Option<u16> {
-        match self.running.borrow().get(hostname) {
-            Some(w) => Some(w.port),
-            None => None,
+    /// Tears down the drained isolate `id` if it has not exited yet.
+    fn abort_draining_isolate(&self, id: u64) {
+        let isolate = self.draining.borrow_mut().remove(&id);
+        if let Some(isolate) = isolate {
+            isolate.task.abort();
         }
+        self.publish_usage();
+    }
+
+    /// Called when the task of isolate `id` completes, drained or not.
+    fn isolate_finished(&self, id: u64) {
+        self.draining.borrow_mut().remove(&id);
+        self.publish_usage();
+    }
+
+    /// Drains every isolate that has not received a request within its idle
+    /// timeout. The next request for an evicted worker cold starts it again.
+    fn evict_idle_isolates(&self) {
+        let now = Instant::now();
+        let idle: Vec<String> = self
+            .running
+            .borrow()
+            .iter()
+            .filter(|(_, w)| w.is_idle(now))
+            .map(|(hostname, _)| hostname.clone())
+            .collect();
+        for hostname in idle {
+            println!("evicting idle isolate for {hostname}");
+            self.drain_isolate(&hostname);
+        }
+        // also refreshes the heap usage and last requests of busy isolates
+        self.publish_usage();
+    }
+
+    /// Publishes what the isolates of this thread use, see [threads::Usage].
+    fn publish_usage(&self) {
+        let running = self.running.borrow();
+        let draining = self.draining.borrow();
+        let heap_used = running
+            .values()
+            .map(|w| w.stats.heap_used.get())
+            .chain(draining.values().map(|d| d.stats.heap_used.get()))
+            .fold(0, usize::saturating_add);
+        // isolates still starting are never evicted
+        let lru = running
+            .values()
+            .filter(|w| *w.ready.borrow())
+            .map(|w| w.last_request.get().into_std())
+            .min();
+        self.threads.usage.publish(
+            self.thread,
+            threads::ThreadSample {
+                isolates: running.len() + draining.len(),
+                heap_used,
+                lru,
+                oldest_draining: draining.keys().min().copied(),
+            },
+        );
+    }
+
+    /// Tears down isolates, on any thread, until starting one more stays
+    /// within the configured number of running isolates and total heap budget.
+    /// Draining isolates count against both and go first, oldest first; then
+    /// the least recently used running isolates that are ready, which are not
+    /// drained as they would stay counted. The returned guard must be held
+    /// until the new isolate is registered.
+    async fn make_room_for_new_isolate(&self) -> tokio::sync::OwnedMutexGuard<()> {
+        let admission = self.threads.usage.admit().await;
+        loop {
+            self.publish_usage();
+            let (count, heap_used) = self.threads.usage.total();
+            let over_count = self.limits.max_running.map_or(false, |max| count >= max);
+            let over_heap = self
+                .limits
+                .max_total_heap_mb
+                .map_or(false, |max| heap_used >= max.saturating_mul(1024 * 1024));
+            if !over_count && !over_heap {
+                return admission;
+            }
+            match self.threads.usage.eviction_candidate() {
+                Some((thread, eviction)) if thread == self.thread => self.evict(eviction),
+                Some((thread, eviction)) => {
+                    let evicted = self
+                        .threads
+                        .run_on(thread, move |state| async move { state.evict(eviction) })
+                        .await;
+                    if evicted.is_err() {
+                        return admission;
+                    }
+                }
+                None => return admission,
+            }
+        }
+    }
+
+    /// Tears down an isolate of this thread to make room for a new one, see
+    /// [IsolateManager::make_room_for_new_isolate].
+    fn evict(&self, eviction: threads::Eviction) {
+        match eviction {
+            threads::Eviction::Draining => {
+                let oldest = self.draining.borrow().keys().min().copied();
+                if let Some(id) = oldest {
+                    println!("tearing down draining isolate {id} to make room");
+                    self.abort_draining_isolate(id);
+                }
+            }
+            threads::Eviction::LeastRecentlyUsed => {
+                let lru = self
+                    .running
+                    .borrow()
+                    .iter()
+                    .filter(|(_, w)| *w.ready.borrow())
+                    .min_by_key(|(_, w)| w.last_request.get())
+                    .map(|(hostname, _)| hostname.clone());
+                if let Some(hostname) = lru {
+                    println!("evicting least recently used isolate for {hostname}");
+                    let worker = self.running.borrow_mut().remove(&hostname);
+                    if let Some(worker) = worker {
+                        worker.task.abort();
+                    }
+                }
+            }
+        }
+        self.publish_usage();
+    }
+
+    /// Drains the isolate serving `hostname` and forgets its crashes, after the
+    /// worker was redeployed or removed.
+    fn worker_changed(&self, hostname: &str) {
+        self.crashes.forget(hostname);
+        if self.store.hostslug_to_worker(hostname.to_string()).is_err() {
+            self.consoles.borrow_mut().remove(hostname);
+        }
+        self.drain_isolate(hostname);
+    }
+
+    /// Tears down the isolate serving `hostname` immediately, dropping any
+    /// in-flight requests, and forgets its crashes.
+    fn shutdown_isolate(&self, hostname: &str) {
+        self.crashes.forget(hostname);
+        let worker = self.running.borrow_mut().remove(hostname);
+        if let Some(worker) = worker {
+            worker.task.abort();
+        }
+        self.publish_usage();
     }
 }
 
+const DEFAULT_MANIFEST_PATH: &str = "./openedge.json";
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let first_arg = std::env::args().nth(1);
+    if first_arg.as_deref() == Some(sandbox::WORKER_FLAG) {
+        std::process::exit(sandbox::run_child());
+    }
+    let manifest_path = first_arg
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_MANIFEST_PATH));
+
+    // this thread only runs the ingress; isolates run on their own threads
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
@@ -107,43 +695,127 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     local.block_on(
         &rt,
         // TODO: rm need for panic
-        startup_ingress().map_err(|e| panic!("failed to startup ingress: {e}")),
+        startup_ingress(&manifest_path).map_err(|e| panic!("failed to startup ingress: {e}")),
     )
 }
 
-async fn startup_ingress() -> Result<(), AnyError> {
-    let mut store = store::Store::default();
-    store.register_module("hello".to_string(), deno_core::resolve_path("./hello.js")?);
-    store.register_module(
-        "goodbye".to_string(),
-        deno_core::resolve_path("./goodbye.js")?,
-    );
-    store.register_module("keys".to_string(), deno_core::resolve_path("./keys.js")?);
+async fn startup_ingress(manifest_path: &Path) -> Result<(), AnyError> {
+    let manifest = config::Manifest::from_file(manifest_path)?;
+    manifest.validate_isolates()?;
+    manifest.validate_tls()?;
+    manifest.validate_zones()?;
+    let manifest_dir = manifest_path.parent().unwrap_or_else(|| Path::new("."));
+    let workers = manifest.resolve(manifest_dir)?;
+    if manifest.zones.is_empty() && workers.values().all(|w| w.domains.is_empty()) {
+        println!("warning: no zones or custom domains are configured, every request will be answered with a 404");
+    }
+    let mut store = store::Store::with_zones(manifest.zones.clone());
+    for (host_slug, worker) in workers.clone() {
+        store.register_worker(host_slug, worker);
+    }
 
-    let state = IsolateManager {
-        running: Rc::new(RefCell::new(HashMap::new())),
-        available_ports: Rc::new(RefCell::new(BTreeSet::from([8888, 9999, 8081]))),
+    let access_log = Arc::new(AccessLog::open(&manifest.access_log, manifest_dir)?);
+    let threads = IsolateThreads::spawn(
         store,
+        manifest.isolates,
+        watchdog::Watchdog::spawn(),
+        access_log.clone(),
+        Arc::new(Metrics::default()),
+        Tracer::spawn(&manifest.tracing)?,
+    )?;
+
+    tokio::task::spawn_local(reload::watch_manifest(
+        manifest_path.to_path_buf(),
+        workers,
+        threads.clone(),
+    ));
+
+    if let Some(admin_config) = admin::AdminConfig::from_env()? {
+        let threads = threads.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = admin::serve(admin_config, threads).await {
+                println!("admin listener failed: {e}");
+            }
+        });
+    }
+
+    let ingress = Ingress {
+        threads,
+        access_log,
+        challenges: acme::Challenges::default(),
+        alt_svc: None,
     };
 
+    if let Some(tls_config) = &manifest.tls {
+        let resolver = Arc::new(tls::SniResolver::load(tls_config, manifest_dir)?);
+        let acceptor = tls::acceptor(resolver.clone());
+        if let Some(acme_config) = &manifest.acme {
+            let certificates = acme::CertificateManager::new(
+                acme_config,
+                manifest_dir,
+                ingress.threads.store.clone(),
+                resolver.clone(),
+                ingress.challenges.clone(),
+            )?;
+            tokio::task::spawn_local(certificates.run());
+        }
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), tls_config.port);
+        let listener = TcpListener::bind(addr).await?;
+        println!("listening for TLS on {addr}");
+        let mut ingress = ingress.clone();
+        if tls_config.http3 {
+            let endpoint = http3::bind(addr, resolver.clone())?;
+            println!("listening for HTTP/3 on {addr}");
+            let http3_ingress = ingress.clone();
+            tokio::task::spawn_local(async move {
+                if let Err(e) = http3::serve(endpoint, http3_ingress).await {
+                    println!("HTTP/3 listener failed: {e}");
+                }
+            });
+            ingress.alt_svc = Some(http3::alt_svc(tls_config.port));
+        }
+        tokio::task::spawn_local(async move {
+            if let Err(e) = tls::serve(listener, acceptor, ingress).await {
+                println!("TLS listener failed: {e}");
+            }
+        });
+    }
+
     let addr: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080);
     let listener = TcpListener::bind(addr).await?;
     println!("listening on {addr}");
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, client) = listener.accept().await?;
+        tokio::task::spawn_local(serve_connection(stream, ingress.clone(), client.ip(), None));
+    }
+}
 
-        let state = state.clone();
-        let service = service_fn(move |req| handle(state.clone(), addr, req));
+/// Serves the requests of one ingress connection over HTTP/1.1 or HTTP/2, told
+/// apart by the HTTP/2 connection preface. This covers h2 negotiated over ALPN
+/// and h2c with prior knowledge; h2c via `Upgrade` is not supported. `sni` is
+/// the hostname the client asked for in its TLS handshake, if any.
+async fn serve_connection<S>(stream: S, ingress: Ingress, client_ip: IpAddr, sni: Option<String>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let service = service_fn(move |req| handle(ingress.clone(), client_ip, sni.clone(), req));
+    if let Err(err) = Http::new()
+        .with_executor(LocalExec)
+        .serve_connection(stream, service)
+        .await
+    {
+        println!("Error serving connection: {:?}", err);
+    }
+}
 
-        tokio::task::spawn_local(async move {
-            if let Err(err) = Http::new()
-                .with_executor(LocalExec)
-                .serve_connection(stream, service)
-                .await
-            {
-                println!("Error serving connection: {:?}", err);
-            }
-        });
+/// The OpenTelemetry `http.flavor` of `version`.
+fn http_flavor(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2.0",
+        Version::HTTP_3 => "3.0",
+        _ => "1.1",
     }
 }
 
@@ -158,3 +830,149 @@ where
         tokio::task::spawn_local(fut);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::client::conn;
+    use tokio_rustls::rustls;
+
+    const UNROUTED: &str = "nope.example.org";
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("openedge-ingress-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An ingress routing the zone `example.com`, without any workers.
+    fn ingress(dir: &Path) -> Ingress {
+        let access_log = config::AccessLogConfig {
+            path: Some(dir.join("access.log")),
+            ..Default::default()
+        };
+        let access_log = Arc::new(AccessLog::open(&access_log, dir).unwrap());
+        let threads = IsolateThreads::spawn(
+            store::Store::with_zones(vec!["example.com".to_string()]),
+            config::IsolatesConfig {
+                threads: Some(1),
+                ..Default::default()
+            },
+            watchdog::Watchdog::spawn(),
+            access_log.clone(),
+            Arc::new(Metrics::default()),
+            Tracer::spawn(&config::TracingConfig::default()).unwrap(),
+        )
+        .unwrap();
+        Ingress {
+            threads,
+            access_log,
+            challenges: acme::Challenges::default(),
+            alt_svc: None,
+        }
+    }
+
+    /// Sends a request for an unrouted hostname and checks the ingress answered
+    /// it over `version` with its 404.
+    async fn assert_not_found(mut sender: conn::SendRequest<Body>, uri: &str, version: Version) {
+        let mut req = Request::get(uri);
+        if version != Version::HTTP_2 {
+            req = req.header(hyper::header::HOST, UNROUTED);
+        }
+        let resp = sender
+            .send_request(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.version(), version);
+        assert_eq!(resp.status(), 404);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, format!("no worker is served at {UNROUTED}\n"));
+    }
+
+    #[tokio::test]
+    async fn serves_http1() {
+        let dir = temp_dir();
+        let ingress = ingress(&dir);
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                let (client, server) = tokio::io::duplex(64 * 1024);
+                let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+                tokio::task::spawn_local(serve_connection(server, ingress, localhost, None));
+                let (sender, connection) = conn::handshake(client).await.unwrap();
+                tokio::task::spawn_local(connection);
+                assert_not_found(sender, "/", Version::HTTP_11).await;
+            })
+            .await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_h2c_with_prior_knowledge() {
+        let dir = temp_dir();
+        let ingress = ingress(&dir);
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                let (client, server) = tokio::io::duplex(64 * 1024);
+                let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+                tokio::task::spawn_local(serve_connection(server, ingress, localhost, None));
+                let (sender, connection) = conn::Builder::new()
+                    .http2_only(true)
+                    .handshake(client)
+                    .await
+                    .unwrap();
+                tokio::task::spawn_local(connection);
+                // routed on :authority
+                let uri = format!("http://{UNROUTED}/");
+                assert_not_found(sender, &uri, Version::HTTP_2).await;
+            })
+            .await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_h2_over_alpn() {
+        let dir = temp_dir();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        let tls_config: config::TlsConfig = serde_json::from_value(serde_json::json!({
+            "certificates": { "*": { "cert": "cert.pem", "key": "key.pem" } }
+        }))
+        .unwrap();
+        let resolver = Arc::new(tls::SniResolver::load(&tls_config, &dir).unwrap());
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let mut client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+        let ingress = ingress(&dir);
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                tokio::task::spawn_local(tls::serve(listener, tls::acceptor(resolver), ingress));
+
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let server_name = "localhost".try_into().unwrap();
+                let stream = connector.connect(server_name, stream).await.unwrap();
+                assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+                let (sender, connection) = conn::Builder::new()
+                    .http2_only(true)
+                    .handshake(stream)
+                    .await
+                    .unwrap();
+                tokio::task::spawn_local(connection);
+                let uri = format!("https://{UNROUTED}/");
+                assert_not_found(sender, &uri, Version::HTTP_2).await;
+            })
+            .await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
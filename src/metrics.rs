@@ -0,0 +1,216 @@
+//! Prometheus metrics of the ingress and its isolates, served as text by the
+//! admin API under `/metrics`. Counters and histograms are recorded as requests
+//! are served; isolate gauges are sampled from the isolate threads on scrape.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Why a request routed to a worker was not answered by its isolate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IsolateError {
+    /// The isolate could not be started, or is crash-looping or backing off.
+    Unavailable,
+    /// The isolate exited before responding.
+    NoResponse,
+    CpuLimit,
+    HeapLimit,
+}
+
+impl IsolateError {
+    fn label(&self) -> &'static str {
+        match self {
+            IsolateError::Unavailable => "unavailable",
+            IsolateError::NoResponse => "no_response",
+            IsolateError::CpuLimit => "cpu_limit",
+            IsolateError::HeapLimit => "heap_limit",
+        }
+    }
+}
+
+/// A running isolate as seen on scrape.
+#[derive(Debug)]
+pub struct IsolateSample {
+    pub host_slug: String,
+    pub id: u64,
+    pub thread: usize,
+    pub heap_used: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    requests: BTreeMap<(String, u16), Histogram>,
+    cold_starts: BTreeMap<String, Histogram>,
+    isolate_errors: BTreeMap<(String, IsolateError), u64>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+impl Metrics {
+    /// Records a request answered by the ingress. `host_slug` is `None` for
+    /// requests that did not name a registered worker, which are counted
+    /// together.
+    pub fn request_served(&self, host_slug: Option<&str>, status: u16, latency: Duration) {
+        let host_slug = host_slug.unwrap_or("").to_string();
+        self.inner
+            .lock()
+            .unwrap()
+            .requests
+            .entry((host_slug, status))
+            .or_default()
+            .observe(latency);
+    }
+
+    pub fn cold_start(&self, host_slug: &str, duration: Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .cold_starts
+            .entry(host_slug.to_string())
+            .or_default()
+            .observe(duration);
+    }
+
+    pub fn isolate_error(&self, host_slug: &str, error: IsolateError) {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .isolate_errors
+            .entry((host_slug.to_string(), error))
+            .or_default() += 1;
+    }
+
+    /// Renders every metric in the Prometheus text format, with `isolates` as
+    /// the currently running isolates and `threads` the number of isolate
+    /// threads.
+    pub fn render(&self, isolates: &[IsolateSample], threads: usize) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP openedge_requests_total Requests answered by the ingress.\n");
+        out.push_str("# TYPE openedge_requests_total counter\n");
+        for ((host_slug, status), histogram) in &inner.requests {
+            let _ = writeln!(
+                out,
+                "openedge_requests_total{{host_slug=\"{}\",status=\"{status}\"}} {}",
+                escape(host_slug),
+                histogram.count
+            );
+        }
+
+        out.push_str(
+            "# HELP openedge_request_duration_seconds Time until the response head was sent.\n",
+        );
+        out.push_str("# TYPE openedge_request_duration_seconds histogram\n");
+        for ((host_slug, status), histogram) in &inner.requests {
+            histogram.render(
+                &mut out,
+                "openedge_request_duration_seconds",
+                &format!("host_slug=\"{}\",status=\"{status}\"", escape(host_slug)),
+            );
+        }
+
+        out.push_str("# HELP openedge_cold_starts_total Isolates started for a request.\n");
+        out.push_str("# TYPE openedge_cold_starts_total counter\n");
+        for (host_slug, histogram) in &inner.cold_starts {
+            let _ = writeln!(
+                out,
+                "openedge_cold_starts_total{{host_slug=\"{}\"}} {}",
+                escape(host_slug),
+                histogram.count
+            );
+        }
+
+        out.push_str(
+            "# HELP openedge_cold_start_duration_seconds Time until a started isolate was ready.\n",
+        );
+        out.push_str("# TYPE openedge_cold_start_duration_seconds histogram\n");
+        for (host_slug, histogram) in &inner.cold_starts {
+            histogram.render(
+                &mut out,
+                "openedge_cold_start_duration_seconds",
+                &format!("host_slug=\"{}\"", escape(host_slug)),
+            );
+        }
+
+        out.push_str("# HELP openedge_isolate_errors_total Requests the isolate did not answer.\n");
+        out.push_str("# TYPE openedge_isolate_errors_total counter\n");
+        for ((host_slug, error), count) in &inner.isolate_errors {
+            let _ = writeln!(
+                out,
+                "openedge_isolate_errors_total{{host_slug=\"{}\",reason=\"{}\"}} {count}",
+                escape(host_slug),
+                error.label()
+            );
+        }
+
+        out.push_str("# HELP openedge_running_isolates Running isolates per isolate thread.\n");
+        out.push_str("# TYPE openedge_running_isolates gauge\n");
+        for thread in 0..threads {
+            let running = isolates.iter().filter(|i| i.thread == thread).count();
+            let _ = writeln!(
+                out,
+                "openedge_running_isolates{{thread=\"{thread}\"}} {running}"
+            );
+        }
+
+        out.push_str("# HELP openedge_isolate_heap_used_bytes V8 heap used by an isolate.\n");
+        out.push_str("# TYPE openedge_isolate_heap_used_bytes gauge\n");
+        for isolate in isolates {
+            let _ = writeln!(
+                out,
+                "openedge_isolate_heap_used_bytes{{host_slug=\"{}\",isolate=\"{}\"}} {}",
+                escape(&isolate.host_slug),
+                isolate.id,
+                isolate.heap_used
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a label value, see the Prometheus text exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use deno_runtime::deno_core::error::AnyError;
+
+use crate::threads::IsolateThreads;
+use crate::{config, store};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Polls the manifest at `path` for changes and applies them to the running
+/// ingress. `applied` is the set of workers most recently loaded from the
+/// manifest, so only entries that changed in the file are touched; workers
+/// registered by other means are left alone.
+///
+/// An invalid manifest is reported and ignored, keeping the previous
+/// configuration in place.
+pub async fn watch_manifest(
+    path: PathBuf,
+    mut applied: BTreeMap<String, store::Worker>,
+    mut threads: IsolateThreads,
+) {
+    let mut last_modified = modified_time(&path).ok();
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = match modified_time(&path) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("failed to stat manifest {}: {e}", path.display());
+                continue;
+            }
+        };
+        if last_modified == Some(modified) {
+            continue;
+        }
+        last_modified = Some(modified);
+
+        let workers = match load(&path) {
+            Ok(w) => w,
+            Err(e) => {
+                println!("not reloading manifest: {e}");
+                continue;
+            }
+        };
+        apply(&mut threads, &applied, &workers);
+        println!("reloaded manifest {}", path.display());
+        applied = workers;
+    }
+}
+
+fn modified_time(path: &Path) -> std::io::Result<SystemTime> {
+    std::fs::metadata(path)?.modified()
+}
+
+fn load(path: &Path) -> Result<BTreeMap<String, store::Worker>, AnyError> {
+    let manifest = config::Manifest::from_file(path)?;
+    manifest.resolve(path.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+/// Applies the difference between two manifests. The store is updated at
+/// once, so no request is routed against a partially applied manifest; the
+/// isolates of removed and changed workers are drained afterwards.
+fn apply(
+    threads: &mut IsolateThreads,
+    old: &BTreeMap<String, store::Worker>,
+    new: &BTreeMap<String, store::Worker>,
+) {
+    let removed: Vec<String> = old
+        .keys()
+        .filter(|host_slug| !new.contains_key(*host_slug))
+        .cloned()
+        .collect();
+    let changed: Vec<(String, store::Worker)> = new
+        .iter()
+        .filter(|(host_slug, worker)| old.get(*host_slug) != Some(worker))
+        .map(|(host_slug, worker)| (host_slug.clone(), worker.clone()))
+        .collect();
+    threads.store.update(&removed, changed.clone());
+    for host_slug in &removed {
+        threads.worker_changed(host_slug);
+    }
+    for (host_slug, _) in &changed {
+        threads.worker_changed(host_slug);
+    }
+}
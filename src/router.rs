@@ -1,47 +1,213 @@
-use crate::{startup_new_worker, worker::wait_until_dials, IsolateManager, RunningIsolateMetadata};
+use crate::store::Store;
+use crate::trace::{SpanKind, TraceContext};
+use crate::{startup_new_worker, IsolateManager, RunningIsolateMetadata};
 use deno_runtime::deno_core::anyhow::anyhow;
 use deno_runtime::deno_core::anyhow::Error;
+use hyper::header::HOST;
+use hyper::http::uri::Authority;
 use hyper::{Body, Request};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::fmt;
 
-pub async fn resolve_to_proxy(
-    mut state: IsolateManager,
-    req: &Request<Body>,
-) -> Result<String, Error> {
-    let headers = req.headers();
-    let header_value = match headers.get(":authority") {
-        Some(a) => a,
-        None => headers.get("host").ok_or(anyhow!(
-            "neither \"host\" nor \":authority\" found in header"
-        ))?,
+/// The worker exists but cannot serve requests right now. Served as a 503.
+#[derive(Debug)]
+pub struct ServiceUnavailable(pub String);
+
+impl fmt::Display for ServiceUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ServiceUnavailable {}
+
+/// No worker is routed to the requested hostname. Served as a 404.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// The host slug of the worker `req` is routed to, see [Store::route]. The
+/// hostname is taken from the URI, which holds `:authority` for HTTP/2, then
+/// from the Host header. `sni`, the hostname of its TLS connection, is used if
+/// it names neither. Fails with [NotFound] for hostnames no worker is routed to.
+pub fn host_slug(store: &Store, req: &Request<Body>, sni: Option<&str>) -> Result<String, Error> {
+    let hostname = match req.uri().authority() {
+        Some(authority) => authority.host().to_string(),
+        None => match req.headers().get(HOST) {
+            Some(value) => value.to_str()?.parse::<Authority>()?.host().to_string(),
+            None => sni
+                .ok_or(anyhow!("neither \":authority\" nor \"host\" found"))?
+                .to_string(),
+        },
     };
-    let host_slug = header_value
-        .to_str()?
-        .split('.')
-        .next()
-        .ok_or(anyhow!("invalid host header"))?;
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    store
+        .route(&hostname)
+        .ok_or_else(|| NotFound(format!("no worker is served at {hostname}")).into())
+}
 
+/// Returns the isolate serving `host_slug`, cold starting one if needed.
+/// Spans are recorded as part of the trace of `trace`.
+pub async fn resolve_to_isolate(
+    mut state: IsolateManager,
+    host_slug: &str,
+    trace: &TraceContext,
+) -> Result<RunningIsolateMetadata, Error> {
     let worker = {
-        match state.get_running_isolate_port(host_slug) {
-            Some(port) => RunningIsolateMetadata { port },
-            None => {
-                let main_module = state.store.hostslug_to_module(host_slug.to_string())?;
-                let new_worker =
-                    startup_new_worker(&mut state, host_slug.to_string(), main_module).await?;
-                let before_coldstart = tokio::time::Instant::now();
-                wait_until_dials(SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-                    new_worker.port,
-                ))
-                .await?;
-                println!(
-                    "cold start took = {}ms",
-                    before_coldstart.elapsed().as_millis()
-                );
-                state.register_new_isolate(host_slug, new_worker.clone());
-                new_worker
+        match state.get_running_isolate(host_slug) {
+            // possibly still starting, in which case this request shares its
+            // cold start
+            Some(worker) => {
+                worker.wait_until_ready().await?;
+                worker
             }
+            None => cold_start(&mut state, host_slug, Some(trace)).await?,
         }
     };
-    Ok(format!("http://127.0.0.1:{}", worker.port))
+    worker.touch();
+    Ok(worker)
+}
+
+/// Starts a new isolate for `host_slug` and waits for its module to evaluate.
+/// The isolate is registered as serving `host_slug` before it is ready, so
+/// concurrent requests wait on this cold start instead of starting their own.
+/// Its span continues the trace of `trace`, or starts a new one.
+pub async fn cold_start(
+    state: &mut IsolateManager,
+    host_slug: &str,
+    trace: Option<&TraceContext>,
+) -> Result<RunningIsolateMetadata, Error> {
+    let mut span = state.tracer.start("cold_start", SpanKind::Internal, trace);
+    span.set_attribute("openedge.host_slug", host_slug);
+    let result = start_isolate(state, host_slug).await;
+    match &result {
+        Ok(worker) => span.set_attribute("openedge.isolate_id", worker.id),
+        Err(_) => span.set_error(),
+    }
+    span.end();
+    result
+}
+
+async fn start_isolate(
+    state: &mut IsolateManager,
+    host_slug: &str,
+) -> Result<RunningIsolateMetadata, Error> {
+    // the worker may have been removed since the request was routed
+    let usercode = state
+        .store
+        .hostslug_to_worker(host_slug.to_string())
+        .map_err(|_| NotFound(format!("worker {host_slug} no longer exists")))?;
+    state.crashes.check(host_slug)?;
+    let before_coldstart = tokio::time::Instant::now();
+    let admission = state.make_room_for_new_isolate().await;
+    let new_worker = startup_new_worker(state, host_slug.to_string(), usercode);
+    state.register_new_isolate(host_slug, new_worker.clone());
+    drop(admission);
+    // the isolate enforces the same timeout itself, see [crate::run_usercode],
+    // as this timer cannot fire while its module blocks the thread
+    let startup_timeout = state.limits.startup_timeout();
+    match tokio::time::timeout(startup_timeout, new_worker.wait_until_ready()).await {
+        Ok(result) => result?,
+        Err(_) => {
+            state.remove_isolate_if_id(host_slug, new_worker.id);
+            new_worker.task.abort();
+            return Err(ServiceUnavailable(format!(
+                "worker did not start within {}ms",
+                startup_timeout.as_millis()
+            ))
+            .into());
+        }
+    }
+    let cold_start_time = before_coldstart.elapsed();
+    println!("cold start took = {}ms", cold_start_time.as_millis());
+    state.metrics.cold_start(host_slug, cold_start_time);
+    Ok(new_worker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Worker;
+    use deno_runtime::deno_core::resolve_url;
+
+    fn store() -> Store {
+        let mut store = Store::with_zones(vec!["example.com".to_string()]);
+        let worker = Worker {
+            domains: vec!["hello.test".to_string()],
+            ..Worker::from(resolve_url("file:///workers/hello.js").unwrap())
+        };
+        store.register_worker("hello".to_string(), worker);
+        store
+    }
+
+    fn request(uri: &str, host: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(host) = host {
+            builder = builder.header(HOST, host);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn route(req: &Request<Body>, sni: Option<&str>) -> Result<String, Error> {
+        host_slug(&store(), req, sni)
+    }
+
+    #[test]
+    fn routes_http1_on_host_header() {
+        let req = request("/", Some("hello.example.com"));
+        assert_eq!(route(&req, None).unwrap(), "hello");
+    }
+
+    #[test]
+    fn routes_http2_on_authority() {
+        let req = request("https://hello.example.com/", None);
+        assert_eq!(route(&req, None).unwrap(), "hello");
+        // :authority wins over a Host header
+        let req = request("https://hello.example.com/", Some("other.example.com"));
+        assert_eq!(route(&req, None).unwrap(), "hello");
+    }
+
+    #[test]
+    fn ignores_port_case_and_trailing_dot() {
+        let req = request("/", Some("Hello.Example.COM.:8080"));
+        assert_eq!(route(&req, None).unwrap(), "hello");
+        let req = request("http://HELLO.example.com:8080/", None);
+        assert_eq!(route(&req, None).unwrap(), "hello");
+    }
+
+    #[test]
+    fn falls_back_to_sni() {
+        let req = request("/", None);
+        assert_eq!(route(&req, Some("hello.example.com")).unwrap(), "hello");
+        assert!(route(&req, None).is_err());
+        // the Host header wins over SNI
+        let req = request("/", Some("other.example.com"));
+        assert!(route(&req, Some("hello.example.com")).is_err());
+    }
+
+    #[test]
+    fn routes_custom_domains() {
+        let req = request("/", Some("hello.test"));
+        assert_eq!(route(&req, None).unwrap(), "hello");
+    }
+
+    #[test]
+    fn unknown_hosts_are_not_found() {
+        for host in [
+            "other.example.com",
+            "hello.example.org",
+            "example.com",
+            "a.hello.example.com",
+            "localhost",
+        ] {
+            let err = route(&request("/", Some(host)), None).unwrap_err();
+            assert!(err.is::<NotFound>(), "{host}: {err}");
+        }
+    }
 }
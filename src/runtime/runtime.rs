@@ -3,10 +3,10 @@ use std::rc::Rc;
 
 use anyhow::anyhow;
 use deno_flash::FlashPermissions;
-use deno_runtime::deno_core::ModuleId;
 use deno_runtime::deno_core::{
     self, anyhow, error::AnyError, Extension, JsRuntime, ModuleSpecifier, RuntimeOptions,
 };
+use deno_runtime::deno_core::{v8, ModuleId};
 use deno_runtime::deno_fetch::FetchPermissions;
 use deno_runtime::deno_net::NetPermissions;
 use deno_runtime::deno_web::TimersPermission;
@@ -20,9 +20,7 @@ use deno_runtime::{
 use crate::located_script_name;
 
 #[derive(Clone)]
-pub struct Permissions {
-    pub allow_local_port: u16,
-}
+pub struct Permissions;
 
 impl TimersPermission for Permissions {
     fn allow_hrtime(&mut self) -> bool {
@@ -46,11 +44,7 @@ impl FlashPermissions for Permissions {
         host: &(T, Option<u16>),
         _api_name: &str,
     ) -> Result<(), AnyError> {
-        match block_local_net(&host.0) {
-            Ok(()) => Ok(()),
-            Err(_) if Some(self.allow_local_port) == host.1 => Ok(()),
-            Err(e) => Err(e),
-        }
+        block_local_net(&host.0)
     }
 }
 
@@ -83,11 +77,7 @@ impl NetPermissions for Permissions {
         host: &(T, Option<u16>),
         _api_name: &str,
     ) -> Result<(), AnyError> {
-        match block_local_net(&host.0) {
-            Ok(()) => Ok(()),
-            Err(_) if Some(self.allow_local_port) == host.1 => Ok(()),
-            Err(e) => Err(e),
-        }
+        block_local_net(&host.0)
     }
 
     fn check_read(&mut self, _p: &std::path::Path, _api_name: &str) -> Result<(), AnyError> {
@@ -108,16 +98,20 @@ impl Runtime {
         main_module: ModuleSpecifier,
         permissions: Permissions,
         options: WorkerOptions,
+        max_heap_bytes: Option<usize>,
     ) -> Self {
         let bootstrap_options = options.bootstrap.clone();
-        let mut worker = Self::from_options(main_module, permissions, options);
+        let mut worker = Self::from_options(main_module, permissions, options, max_heap_bytes);
         worker.bootstrap(&bootstrap_options);
         worker
     }
+    /// Like deno's `MainWorker::from_options`. `max_heap_bytes` caps the V8 heap;
+    /// without it V8 picks a limit based on system memory.
     pub fn from_options(
         main_module: ModuleSpecifier,
         permissions: Permissions,
         mut options: WorkerOptions,
+        max_heap_bytes: Option<usize>,
     ) -> Self {
         // Permissions: many ops depend on this
         let unstable = options.bootstrap.unstable;
@@ -198,6 +192,10 @@ impl Runtime {
 
         let js_runtime = JsRuntime::new(RuntimeOptions {
             module_loader: Some(options.module_loader.clone()),
+            // TODO: boot from a per-worker snapshot taken after evaluating the user
+            // module. deno_core 0.153 asserts that no startup snapshot is loaded when
+            // creating one, and deno_runtime's bootstrap JS only ships inside
+            // deno_isolate_init(), so this needs a deno_core upgrade first.
             startup_snapshot: Some(deno_runtime::js::deno_isolate_init()),
             // startup_snapshot: None,
             source_map_getter: options.source_map_getter,
@@ -205,6 +203,8 @@ impl Runtime {
             shared_array_buffer_store: options.shared_array_buffer_store.clone(),
             compiled_wasm_module_store: options.compiled_wasm_module_store.clone(),
             extensions,
+            create_params: max_heap_bytes
+                .map(|max| v8::CreateParams::default().heap_limits(0, max)),
             ..Default::default()
         });
 
@@ -223,6 +223,26 @@ impl Runtime {
         self.js_runtime.run_event_loop(wait_for_inspector).await
     }
 
+    /// Calls `on_limit` and terminates execution when the isolate is about to
+    /// run out of heap, instead of letting V8 abort the process.
+    pub fn terminate_near_heap_limit(&mut self, mut on_limit: impl FnMut() + 'static) {
+        let handle = self.js_runtime.v8_isolate().thread_safe_handle();
+        self.js_runtime
+            .add_near_heap_limit_callback(move |current_limit, _initial_limit| {
+                on_limit();
+                handle.terminate_execution();
+                // V8 aborts if it still can't allocate after this returns, so give
+                // the isolate room to unwind the termination
+                current_limit * 2
+            });
+    }
+
+    pub fn used_heap_size(&mut self) -> usize {
+        let mut stats = v8::HeapStatistics::default();
+        self.js_runtime.v8_isolate().get_heap_statistics(&mut stats);
+        stats.used_heap_size()
+    }
+
     pub fn bootstrap(&mut self, options: &deno_runtime::BootstrapOptions) {
         let script = format!("bootstrap.mainRuntime({})", options.as_json());
         self.execute_script(&located_script_name!(), &script)
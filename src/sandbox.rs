@@ -0,0 +1,704 @@
+//! Sandboxed isolates. With `isolates.sandbox` set, each isolate runs in a child
+//! process (this binary, started with [WORKER_FLAG]) so a crash in V8 or an
+//! extension only takes down one worker. The child has no filesystem access,
+//! runs under a seccomp allowlist and rlimits, and talks to the ingress over a
+//! Unix socket: one JSON frame each way to start up, then HTTP/2.
+use std::convert::Infallible;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process::Stdio;
+use std::rc::Rc;
+use std::time::Duration;
+
+use deno_runtime::deno_core;
+use deno_runtime::deno_core::anyhow::anyhow;
+use deno_runtime::deno_core::error::AnyError;
+use deno_runtime::deno_core::futures::future::poll_fn;
+use hyper::body::HttpBody;
+use hyper::client::conn;
+use hyper::header::{HeaderValue, HOST};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Uri};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::process::ChildStdout;
+use tokio::sync::{mpsc, watch};
+
+use crate::config::Limits;
+use crate::console::{ConsoleLine, WorkerConsole};
+use crate::dispatch::{self, IsolateRequest};
+use crate::loader::OnlyLoadWrapperImports;
+use crate::watchdog::Watchdog;
+use crate::worker::{self, ExitReason, IsolateStats, Startup};
+use crate::{store, LocalExec};
+
+/// Starts this binary as a sandboxed isolate instead of an ingress.
+pub const WORKER_FLAG: &str = "--worker";
+
+/// The child's end of the socket is always this descriptor.
+const CHILD_FD: i32 = 3;
+
+const MAX_OPEN_FILES: libc::rlim_t = 256;
+
+/// Processes and threads the child's user may have. The kernel counts them per
+/// user and does not enforce the limit for root; new processes are also denied
+/// by the seccomp filter.
+const MAX_PROCESSES: libc::rlim_t = 16_384;
+
+/// Largest startup frame either side accepts. The spawn frame carries the
+/// worker's source.
+const MAX_FRAME_BYTES: u32 = 64 * 1024 * 1024;
+
+/// Longest line read from a child's stdout, enough for a console line with a
+/// message of the maximum length. Longer lines are cut off.
+const MAX_CONSOLE_LINE_BYTES: usize = 128 * 1024;
+
+/// Carries the child's heap usage on every response. Stripped by the ingress.
+const HEAP_USED_HEADER: &str = "x-openedge-heap-used";
+
+/// Sent by the ingress to start the child's isolate.
+#[derive(Serialize, Deserialize)]
+struct Spawn {
+    host_slug: String,
+    module: String,
+    source: String,
+    env_vars: Vec<(String, String)>,
+    limits: Limits,
+    startup_timeout_ms: u64,
+}
+
+/// Sent by the child once the user module has been evaluated, or failed to.
+#[derive(Serialize, Deserialize)]
+struct Started {
+    error: Option<String>,
+    heap_used: usize,
+}
+
+/// Like [worker::run_usercode], but runs the isolate in a sandboxed child
+/// process and forwards `requests` to it.
+pub async fn run_sandboxed(
+    usercode: store::Worker,
+    requests: mpsc::UnboundedReceiver<IsolateRequest>,
+    startup: Startup,
+    stats: Rc<IsolateStats>,
+    console: WorkerConsole,
+) -> Result<(), AnyError> {
+    let path = usercode
+        .module
+        .to_file_path()
+        .map_err(|_| anyhow!("module {} is not a file", usercode.module))?;
+    let source = tokio::fs::read_to_string(path).await?;
+
+    let (stream, child_end) = std::os::unix::net::UnixStream::pair()?;
+    let child_fd = child_end.as_raw_fd();
+    let mut command = tokio::process::Command::new(std::env::current_exe()?);
+    command
+        .arg(WORKER_FLAG)
+        .env_clear()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    if let Ok(region) = std::env::var("FLY_REGION") {
+        command.env("FLY_REGION", region);
+    }
+    // Safety: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 clears close-on-exec, except when the descriptors are equal
+            let result = if child_fd == CHILD_FD {
+                libc::fcntl(CHILD_FD, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(child_fd, CHILD_FD)
+            };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    drop(child_end);
+    if let Some(stdout) = child.stdout.take() {
+        tokio::task::spawn_local(record_console(stdout, console.clone()));
+    }
+
+    stream.set_nonblocking(true)?;
+    let mut stream = UnixStream::from_std(stream)?;
+    write_frame(
+        &mut stream,
+        &Spawn {
+            host_slug: console.host_slug().to_string(),
+            module: usercode.module.to_string(),
+            source,
+            env_vars: usercode.env_vars,
+            limits: usercode.limits,
+            startup_timeout_ms: startup.timeout.as_millis() as u64,
+        },
+    )
+    .await?;
+    let started: Started = match read_frame(&mut stream).await {
+        Ok(started) => started,
+        Err(e) => return Err(child_exited(child.wait().await?, &stats).unwrap_or(e)),
+    };
+    if let Some(error) = started.error {
+        return Err(child_exited(child.wait().await?, &stats).unwrap_or_else(|| anyhow!(error)));
+    }
+    stats.heap_used.set(started.heap_used);
+
+    let (sender, connection) = conn::Builder::new()
+        .http2_only(true)
+        .executor(LocalExec)
+        .handshake::<_, Body>(stream)
+        .await?;
+    // the router may have given up waiting, which is fine
+    let _ = startup.ready.send(true);
+
+    let forwarding = forward_requests(requests, sender, stats.clone());
+    tokio::pin!(forwarding, connection);
+    let mut forwarding_done = false;
+    let connection_result = loop {
+        tokio::select! {
+            _ = &mut forwarding, if !forwarding_done => forwarding_done = true,
+            result = &mut connection => break result,
+        }
+    };
+    let status = child.wait().await?;
+    match child_exited(status, &stats) {
+        Some(e) => Err(e),
+        None => Ok(connection_result?),
+    }
+}
+
+/// Passes the console lines a child writes to `stdout` on to `console`, and
+/// anything else the child prints to our stdout.
+async fn record_console(stdout: ChildStdout, console: WorkerConsole) {
+    let mut stdout = BufReader::new(stdout);
+    let mut line = Vec::new();
+    while let Ok(true) = read_line(&mut stdout, &mut line, MAX_CONSOLE_LINE_BYTES).await {
+        match serde_json::from_slice::<ConsoleLine>(&line) {
+            Ok(line) => console.record(line),
+            Err(_) => println!("{}", String::from_utf8_lossy(&line)),
+        }
+    }
+}
+
+/// Reads the next line of `reader` into `line`, without its newline, keeping
+/// at most `max` bytes of it. Returns `false` at the end of `reader`.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    max: usize,
+) -> io::Result<bool> {
+    line.clear();
+    if (&mut *reader)
+        .take(max as u64)
+        .read_until(b'\n', line)
+        .await?
+        == 0
+    {
+        return Ok(false);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        return Ok(true);
+    }
+    // the line is longer than `max`; skip the rest of it
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(true);
+        }
+        match available.iter().position(|b| *b == b'\n') {
+            Some(newline) => {
+                reader.consume(newline + 1);
+                return Ok(true);
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// The error a child exiting with `status` is reported as, if any.
+fn child_exited(status: std::process::ExitStatus, stats: &IsolateStats) -> Option<AnyError> {
+    if status.success() {
+        return None;
+    }
+    match status.code().and_then(exit_reason) {
+        Some(reason) => {
+            stats.exit_reason.set(Some(reason));
+            Some(anyhow!(reason.message()))
+        }
+        None => Some(anyhow!("sandboxed worker exited with {status}")),
+    }
+}
+
+fn exit_code(reason: ExitReason) -> i32 {
+    match reason {
+        ExitReason::CpuLimit => 10,
+        ExitReason::HeapLimit => 11,
+        ExitReason::StartupTimeout => 12,
+    }
+}
+
+fn exit_reason(code: i32) -> Option<ExitReason> {
+    match code {
+        10 => Some(ExitReason::CpuLimit),
+        11 => Some(ExitReason::HeapLimit),
+        12 => Some(ExitReason::StartupTimeout),
+        _ => None,
+    }
+}
+
+/// Sends each request to the child on its own HTTP/2 stream until `requests`
+/// is closed.
+async fn forward_requests(
+    mut requests: mpsc::UnboundedReceiver<IsolateRequest>,
+    mut sender: conn::SendRequest<Body>,
+    stats: Rc<IsolateStats>,
+) {
+    while let Some(IsolateRequest { mut req, respond }) = requests.recv().await {
+        // the connection is gone; dropping `respond` fails the dispatch
+        if poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+            return;
+        }
+        set_absolute_uri(&mut req);
+        let response = sender.send_request(req);
+        let stats = stats.clone();
+        let stats_id = stats.request_started();
+        tokio::task::spawn_local(async move {
+            if let Ok(mut resp) = response.await {
+                if let Some(heap_used) = resp.headers_mut().remove(HEAP_USED_HEADER) {
+                    if let Some(heap_used) = heap_used.to_str().ok().and_then(|h| h.parse().ok()) {
+                        stats.heap_used.set(heap_used);
+                    }
+                }
+                let (parts, body) = resp.into_parts();
+                let (body_tx, proxied) = Body::channel();
+                if respond.send(Response::from_parts(parts, proxied)).is_ok() {
+                    forward_body(body, body_tx).await;
+                }
+            }
+            // otherwise dropping `respond` fails the dispatch
+            stats.request_finished(stats_id);
+        });
+    }
+}
+
+/// Streams the response body of the child to the ingress, so the request
+/// counts as in flight until it has been sent completely.
+async fn forward_body(mut body: Body, mut tx: hyper::body::Sender) {
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => {
+                if tx.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+            Err(_) => return tx.abort(),
+        }
+    }
+    if let Ok(Some(trailers)) = body.trailers().await {
+        let _ = tx.send_trailers(trailers).await;
+    }
+}
+
+/// HTTP/2 needs a scheme and authority, which requests received over HTTP/1
+/// only carry in the Host header.
+fn set_absolute_uri(req: &mut Request<Body>) {
+    let authority = req
+        .uri()
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| req.headers().get(HOST).and_then(|h| h.to_str().ok()))
+        .unwrap_or("localhost")
+        .to_string();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/")
+        .to_string();
+    if let Ok(uri) = Uri::builder()
+        .scheme("http")
+        .authority(authority)
+        .path_and_query(path)
+        .build()
+    {
+        *req.uri_mut() = uri;
+    }
+}
+
+/// Entry point of a sandboxed child. Returns its exit code.
+pub fn run_child() -> i32 {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build runtime");
+    let local = tokio::task::LocalSet::new();
+    match local.block_on(&rt, serve_child()) {
+        Ok(None) => 0,
+        Ok(Some(reason)) => exit_code(reason),
+        Err(e) => {
+            println!("sandboxed worker failed: {e}");
+            1
+        }
+    }
+}
+
+async fn serve_child() -> Result<Option<ExitReason>, AnyError> {
+    // Safety: the ingress passes the socket as CHILD_FD, see run_sandboxed
+    let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(CHILD_FD) };
+    stream.set_nonblocking(true)?;
+    let mut stream = UnixStream::from_std(stream)?;
+    let spawn: Spawn = read_frame(&mut stream).await?;
+
+    // name resolution loads its config and modules from disk on first use
+    let _ = ("localhost", 0).to_socket_addrs();
+    restrict()?;
+
+    let module = deno_core::resolve_url(&spawn.module)?;
+    let runtime = worker::instance_with_loader(
+        module.clone(),
+        spawn
+            .limits
+            .max_heap_mb
+            .map(|mb| mb.saturating_mul(1024 * 1024)),
+        OnlyLoadWrapperImports::preloaded(module.clone(), spawn.source),
+    )?;
+    let usercode = store::Worker {
+        module,
+        env_vars: spawn.env_vars,
+        limits: spawn.limits,
+        idle_timeout: None,
+        domains: vec![],
+    };
+
+    let (requests, requests_rx) = mpsc::unbounded_channel();
+    let (ready, mut ready_rx) = watch::channel(false);
+    let stats = Rc::new(IsolateStats::default());
+    let mut isolate = tokio::task::spawn_local(worker::run_usercode(
+        usercode,
+        Some(runtime),
+        requests_rx,
+        Startup {
+            ready,
+            timeout: Duration::from_millis(spawn.startup_timeout_ms),
+        },
+        stats.clone(),
+        Watchdog::spawn(),
+        WorkerConsole::forwarding(spawn.host_slug),
+    ));
+    while !*ready_rx.borrow() {
+        if ready_rx.changed().await.is_err() {
+            break;
+        }
+    }
+    if !*ready_rx.borrow() {
+        let error = match (&mut isolate).await {
+            Ok(Err(e)) => e.to_string(),
+            _ => "worker exited before becoming ready".to_string(),
+        };
+        write_frame(
+            &mut stream,
+            &Started {
+                error: Some(error),
+                heap_used: 0,
+            },
+        )
+        .await?;
+        return Ok(stats.exit_reason.get());
+    }
+    write_frame(
+        &mut stream,
+        &Started {
+            error: None,
+            heap_used: stats.heap_used.get(),
+        },
+    )
+    .await?;
+
+    let service_stats = stats.clone();
+    let service = service_fn(move |req| {
+        let requests = requests.clone();
+        let stats = service_stats.clone();
+        async move {
+            let mut resp = match dispatch::dispatch(&requests, req).await {
+                Ok(resp) => resp,
+                Err(_) => Response::builder().status(502).body(Body::empty()).unwrap(),
+            };
+            resp.headers_mut()
+                .insert(HEAP_USED_HEADER, HeaderValue::from(stats.heap_used.get()));
+            Ok::<_, Infallible>(resp)
+        }
+    });
+    let connection = Http::new()
+        .with_executor(LocalExec)
+        .http2_only(true)
+        .serve_connection(stream, service);
+    let result = tokio::select! {
+        // the ingress is done with this isolate; let in-flight requests finish
+        result = connection => {
+            result?;
+            isolate.await
+        }
+        result = &mut isolate => result,
+    };
+    match result {
+        Ok(Ok(())) => Ok(None),
+        Ok(Err(e)) => match stats.exit_reason.get() {
+            Some(reason) => Ok(Some(reason)),
+            None => Err(e),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Applies the child's rlimits and seccomp filter. Threads started afterwards,
+/// including V8's, inherit the filter.
+fn restrict() -> Result<(), AnyError> {
+    for (resource, limit) in [
+        (libc::RLIMIT_CORE, 0),
+        // stdout and stderr are pipes, so this only stops writing files
+        (libc::RLIMIT_FSIZE, 0),
+        (libc::RLIMIT_NOFILE, MAX_OPEN_FILES),
+        (libc::RLIMIT_NPROC, MAX_PROCESSES),
+    ] {
+        let rlimit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        // Safety: rlimit is a valid pointer for the duration of the call
+        if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    let arch = std::env::consts::ARCH.try_into()?;
+    let allowed: BpfProgram = SeccompFilter::new(
+        allowed_syscalls()?,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        arch,
+    )?
+    .try_into()?;
+    // clone3 passes its flags in memory the filter cannot read. Failing it with
+    // ENOSYS makes libc fall back to clone, whose flags are checked. The most
+    // recently applied filter decides between two errnos.
+    let no_clone3: BpfProgram = SeccompFilter::new(
+        [(libc::SYS_clone3, vec![])].into(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::ENOSYS as u32),
+        arch,
+    )?
+    .try_into()?;
+    seccompiler::apply_filter(&allowed)?;
+    seccompiler::apply_filter(&no_clone3)?;
+    Ok(())
+}
+
+/// Syscalls the sandbox may make; all others fail with `EPERM`. This covers
+/// memory and threads, signals to the child itself, timers, the event loop and
+/// IP sockets, but no opening of files, no Unix sockets and no new processes.
+/// `newfstatat` stays allowed because libc's resolver stats
+/// `/etc/resolv.conf` before every lookup; it only reveals metadata.
+fn allowed_syscalls() -> Result<std::collections::BTreeMap<i64, Vec<SeccompRule>>, AnyError> {
+    let mut allowed: Vec<i64> = vec![
+        // descriptors
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_close,
+        libc::SYS_fcntl,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_eventfd2,
+        // memory
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mprotect,
+        libc::SYS_mremap,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_membarrier,
+        libc::SYS_pkey_alloc,
+        libc::SYS_pkey_free,
+        libc::SYS_pkey_mprotect,
+        // threads and signals
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_gettid,
+        libc::SYS_getpid,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        // time and system information
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        libc::SYS_getrandom,
+        libc::SYS_getrusage,
+        libc::SYS_uname,
+        libc::SYS_sysinfo,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        // event loop
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_ppoll,
+        // sockets
+        libc::SYS_connect,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_getsockopt,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_shutdown,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    allowed.extend([libc::SYS_epoll_wait, libc::SYS_poll, libc::SYS_getrlimit]);
+
+    let mut rules: std::collections::BTreeMap<i64, Vec<SeccompRule>> = allowed
+        .into_iter()
+        .map(|syscall| (syscall, vec![]))
+        .collect();
+    let arg = SeccompCondition::new;
+    // threads only, no new processes
+    rules.insert(
+        libc::SYS_clone,
+        vec![SeccompRule::new(vec![arg(
+            0,
+            SeccompCmpArgLen::Qword,
+            SeccompCmpOp::MaskedEq(libc::CLONE_THREAD as u64),
+            libc::CLONE_THREAD as u64,
+        )?])?],
+    );
+    rules.insert(
+        libc::SYS_socket,
+        vec![SeccompRule::new(vec![arg(
+            0,
+            SeccompCmpArgLen::Dword,
+            SeccompCmpOp::Ne,
+            libc::AF_UNIX as u64,
+        )?])?],
+    );
+    // reading the child's own limits
+    rules.insert(
+        libc::SYS_prlimit64,
+        vec![SeccompRule::new(vec![
+            arg(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, 0)?,
+            arg(2, SeccompCmpArgLen::Qword, SeccompCmpOp::Eq, 0)?,
+        ])?],
+    );
+    rules.insert(
+        libc::SYS_ioctl,
+        [libc::FIONBIO, libc::FIOCLEX]
+            .into_iter()
+            .map(|request| {
+                SeccompRule::new(vec![arg(
+                    1,
+                    SeccompCmpArgLen::Dword,
+                    SeccompCmpOp::Eq,
+                    request,
+                )?])
+            })
+            .collect::<Result<_, _>>()?,
+    );
+    rules.insert(
+        libc::SYS_prctl,
+        [libc::PR_SET_NAME, libc::PR_GET_NAME]
+            .into_iter()
+            .map(|option| {
+                SeccompRule::new(vec![arg(
+                    0,
+                    SeccompCmpArgLen::Dword,
+                    SeccompCmpOp::Eq,
+                    option as u64,
+                )?])
+            })
+            .collect::<Result<_, _>>()?,
+    );
+    // Safety: getpid has no preconditions
+    let pid = unsafe { libc::getpid() } as u64;
+    for syscall in [libc::SYS_kill, libc::SYS_tgkill] {
+        rules.insert(
+            syscall,
+            vec![SeccompRule::new(vec![arg(
+                0,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::Eq,
+                pid,
+            )?])?],
+        );
+    }
+    Ok(rules)
+}
+
+async fn write_frame<T: Serialize>(stream: &mut UnixStream, value: &T) -> Result<(), AnyError> {
+    let frame = serde_json::to_vec(value)?;
+    let len = u32::try_from(frame.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_BYTES)
+        .ok_or_else(|| anyhow!("frame of {} bytes is too large", frame.len()))?;
+    stream.write_u32(len).await?;
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn read_frame<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<T, AnyError> {
+    let len = stream.read_u32().await?;
+    if len > MAX_FRAME_BYTES {
+        return Err(anyhow!("frame of {len} bytes is too large"));
+    }
+    let mut frame = vec![0; len as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(serde_json::from_slice(&frame)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cuts_off_long_console_lines() {
+        let mut stdout: &[u8] = b"short\n0123456789\nlast";
+        let mut line = Vec::new();
+        let mut lines = vec![];
+        while read_line(&mut stdout, &mut line, 8).await.unwrap() {
+            lines.push(String::from_utf8(line.clone()).unwrap());
+        }
+        assert_eq!(lines, ["short", "01234567", "last"]);
+    }
+}
@@ -0,0 +1,91 @@
+// Appended to the generated wrapper module, where `worker` is the user module's
+// default export and `env` its environment. Pulls requests dispatched by the
+// ingress and streams each response back.
+const core = Deno.core;
+
+function requestBody(rid) {
+  return new ReadableStream({
+    async pull(controller) {
+      const chunk = await core.opAsync("op_request_body_read", rid);
+      if (chunk === null) {
+        controller.close();
+      } else {
+        controller.enqueue(chunk);
+      }
+    },
+  });
+}
+
+async function respond(rid, resp) {
+  const headers = [...resp.headers.entries()];
+  const body = resp.body;
+  core.ops.op_respond(rid, resp.status, headers, body !== null);
+  if (body === null) {
+    return;
+  }
+  const reader = body.getReader();
+  try {
+    while (true) {
+      const { done, value } = await reader.read();
+      if (done) {
+        break;
+      }
+      await core.opAsync("op_response_body_write", rid, value);
+    }
+  } finally {
+    core.close(rid);
+  }
+}
+
+// The requests being handled, read by src/console.js and src/fetch.js.
+const requests = globalThis[Symbol.for("openedge.requests")];
+
+async function handle({ rid, method, url, headers, hasBody }) {
+  let resp;
+  let request = null;
+  try {
+    const req = new Request(url, {
+      method,
+      headers,
+      body: hasBody ? requestBody(rid) : null,
+    });
+    request = {
+      id: req.headers.get("x-request-id"),
+      traceparent: req.headers.get("traceparent"),
+      tracestate: req.headers.get("tracestate"),
+    };
+    requests.inFlight.set(request.id, request);
+    let pending;
+    requests.running = request;
+    try {
+      pending = worker.fetch(req, env);
+    } finally {
+      requests.running = null;
+    }
+    resp = await pending;
+  } catch (e) {
+    console.log(e);
+    resp = new Response("internal server error\n", { status: 500 });
+  }
+  try {
+    await respond(rid, resp);
+  } catch (e) {
+    console.log(e);
+    // closing the request fails it on the ingress side if no head was sent
+    core.tryClose(rid);
+  } finally {
+    requests.inFlight.delete(request?.id);
+  }
+}
+
+async function serve() {
+  while (true) {
+    const next = await core.opAsync("op_next_request");
+    if (next === null) {
+      return;
+    }
+    handle(next).catch((e) => console.log(e));
+  }
+}
+
+serve();
@@ -1,25 +1,239 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use deno_runtime::deno_core::anyhow;
 use deno_runtime::deno_core::{error::AnyError, ModuleSpecifier};
 
+use crate::config::{Limits, DEFAULT_IDLE_TIMEOUT_SECS};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Worker {
+    pub module: ModuleSpecifier,
+    pub env_vars: Vec<(String, String)>,
+    pub limits: Limits,
+    /// How long the worker's isolate may go without requests before it is shut
+    /// down. `None` keeps it running indefinitely.
+    pub idle_timeout: Option<Duration>,
+    /// Custom domains routed to the worker, with certificates from ACME.
+    pub domains: Vec<String>,
+}
+
+impl From<ModuleSpecifier> for Worker {
+    fn from(module: ModuleSpecifier) -> Self {
+        Worker {
+            module,
+            env_vars: vec![],
+            limits: Limits::default(),
+            idle_timeout: Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS)),
+            domains: vec![],
+        }
+    }
+}
+
+/// Registered workers, shared by the ingress and every isolate thread.
 #[derive(Default, Clone, Debug)]
 pub struct Store {
-    store: Rc<RefCell<HashMap<String, ModuleSpecifier>>>,
+    registry: Arc<RwLock<Registry>>,
+    /// See [crate::config::Manifest::zones].
+    zones: Arc<Vec<String>>,
+}
+
+/// Workers and their custom domains, behind a single lock so they are always
+/// updated together.
+#[derive(Default, Debug)]
+struct Registry {
+    workers: HashMap<String, Worker>,
+    /// Host slug of the worker each custom domain is routed to.
+    domains: HashMap<String, String>,
+}
+
+impl Registry {
+    fn insert(&mut self, host_slug: String, worker: Worker) {
+        self.domains.retain(|_, slug| *slug != host_slug);
+        for domain in &worker.domains {
+            self.domains.insert(domain.clone(), host_slug.clone());
+        }
+        self.workers.insert(host_slug, worker);
+    }
+
+    fn remove(&mut self, host_slug: &str) -> Option<Worker> {
+        self.domains.retain(|_, slug| slug != host_slug);
+        self.workers.remove(host_slug)
+    }
 }
 
 impl Store {
-    pub fn register_module(&mut self, host_slug: String, module: ModuleSpecifier) {
-        self.store.borrow_mut().insert(host_slug, module);
+    pub fn with_zones(zones: Vec<String>) -> Self {
+        Store {
+            zones: Arc::new(zones),
+            ..Default::default()
+        }
+    }
+
+    /// Points worker `host_slug` at `module`, keeping the rest of its
+    /// configuration, or registers it with the defaults if it is new. Returns
+    /// whether the worker existed.
+    pub fn register_module(&mut self, host_slug: String, module: ModuleSpecifier) -> bool {
+        let mut registry = self.registry.write().unwrap();
+        match registry.workers.get_mut(&host_slug) {
+            Some(worker) => {
+                worker.module = module;
+                true
+            }
+            None => {
+                registry.insert(host_slug, module.into());
+                false
+            }
+        }
+    }
+
+    pub fn register_worker(&mut self, host_slug: String, worker: Worker) {
+        self.registry.write().unwrap().insert(host_slug, worker);
+    }
+
+    pub fn unregister(&mut self, host_slug: &str) -> Option<Worker> {
+        self.registry.write().unwrap().remove(host_slug)
+    }
+
+    /// Unregisters `removed` and registers `changed` at once, so no request is
+    /// routed against a partial update.
+    pub fn update(&mut self, removed: &[String], changed: Vec<(String, Worker)>) {
+        let mut registry = self.registry.write().unwrap();
+        for host_slug in removed {
+            registry.remove(host_slug);
+        }
+        for (host_slug, worker) in changed {
+            registry.insert(host_slug, worker);
+        }
+    }
+
+    /// The host slug of the worker `domain` is routed to, if any.
+    pub fn host_slug_for_domain(&self, domain: &str) -> Option<String> {
+        self.registry.read().unwrap().domains.get(domain).cloned()
     }
 
-    pub fn hostslug_to_module(&self, hostname: String) -> Result<ModuleSpecifier, AnyError> {
-        self.store
-            .borrow()
+    /// Every custom domain with the host slug it is routed to.
+    pub fn domains(&self) -> Vec<(String, String)> {
+        let mut domains: Vec<(String, String)> = self
+            .registry
+            .read()
+            .unwrap()
+            .domains
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        domains.sort();
+        domains
+    }
+
+    /// The host slug of the worker `hostname` is routed to, if any: the worker
+    /// it is a custom domain of, or the worker named by its first label if the
+    /// rest is a zone. `hostname` must be lowercase.
+    pub fn route(&self, hostname: &str) -> Option<String> {
+        let registry = self.registry.read().unwrap();
+        if let Some(host_slug) = registry.domains.get(hostname) {
+            return Some(host_slug.clone());
+        }
+        let (host_slug, zone) = hostname.split_once('.')?;
+        if self.zones.iter().any(|z| z == zone) && registry.workers.contains_key(host_slug) {
+            return Some(host_slug.to_string());
+        }
+        None
+    }
+
+    pub fn list(&self) -> Vec<(String, Worker)> {
+        let mut workers: Vec<(String, Worker)> = self
+            .registry
+            .read()
+            .unwrap()
+            .workers
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        workers.sort_by(|a, b| a.0.cmp(&b.0));
+        workers
+    }
+
+    pub fn contains(&self, host_slug: &str) -> bool {
+        self.registry
+            .read()
+            .unwrap()
+            .workers
+            .contains_key(host_slug)
+    }
+
+    pub fn hostslug_to_worker(&self, hostname: String) -> Result<Worker, AnyError> {
+        self.registry
+            .read()
+            .unwrap()
+            .workers
             .get(&hostname)
             .ok_or_else(|| anyhow::anyhow!("hostname not found"))
             .map(|a| a.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deno_runtime::deno_core::resolve_url;
+
+    fn module(name: &str) -> ModuleSpecifier {
+        resolve_url(&format!("file:///workers/{name}.js")).unwrap()
+    }
+
+    fn worker(domains: &[&str]) -> Worker {
+        Worker {
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+            ..Worker::from(module("hello"))
+        }
+    }
+
+    #[test]
+    fn routes_zones_and_domains() {
+        let mut store = Store::with_zones(vec!["example.com".to_string(), "edge.dev".to_string()]);
+        store.register_worker("hello".to_string(), worker(&["hello.test"]));
+        assert_eq!(store.route("hello.example.com").as_deref(), Some("hello"));
+        assert_eq!(store.route("hello.edge.dev").as_deref(), Some("hello"));
+        assert_eq!(store.route("hello.test").as_deref(), Some("hello"));
+        assert_eq!(store.route("other.example.com"), None);
+        assert_eq!(store.route("hello.other.com"), None);
+        assert_eq!(store.route("www.hello.test"), None);
+    }
+
+    #[test]
+    fn reregistering_replaces_domains() {
+        let mut store = Store::default();
+        store.register_worker("hello".to_string(), worker(&["a.test"]));
+        store.register_worker("hello".to_string(), worker(&["b.test"]));
+        assert_eq!(store.route("a.test"), None);
+        assert_eq!(store.route("b.test").as_deref(), Some("hello"));
+        store.unregister("hello");
+        assert_eq!(store.route("b.test"), None);
+    }
+
+    #[test]
+    fn register_module_keeps_configuration() {
+        let mut store = Store::default();
+        store.register_worker("hello".to_string(), worker(&["hello.test"]));
+        assert!(store.register_module("hello".to_string(), module("v2")));
+        let updated = store.hostslug_to_worker("hello".to_string()).unwrap();
+        assert_eq!(updated.module, module("v2"));
+        assert_eq!(updated.domains, vec!["hello.test".to_string()]);
+        assert!(!store.register_module("new".to_string(), module("new")));
+    }
+
+    #[test]
+    fn update_applies_removals_and_changes() {
+        let mut store = Store::default();
+        store.register_worker("a".to_string(), worker(&["a.test"]));
+        store.register_worker("b".to_string(), worker(&[]));
+        store.update(
+            &["a".to_string()],
+            vec![("b".to_string(), worker(&["a.test"]))],
+        );
+        assert!(!store.contains("a"));
+        assert_eq!(store.route("a.test").as_deref(), Some("b"));
+    }
+}
@@ -0,0 +1,205 @@
+//! Restarts isolates that fail or exit on their own, backing off exponentially
+//! between consecutive crashes. A worker that keeps crashing is marked as
+//! crash-looping and served a 503 until a longer backoff expires, it is
+//! redeployed or restarted. Isolates terminated for exceeding their limits did
+//! not crash; the next request cold starts them.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use deno_runtime::deno_core::error::AnyError;
+use tokio::time::Instant;
+
+use crate::router::{self, ServiceUnavailable};
+use crate::worker::{ExitReason, IsolateStats};
+use crate::IsolateManager;
+
+/// Consecutive crashes after which a worker is no longer restarted.
+const CRASH_LOOP_THRESHOLD: u32 = 5;
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// How long a crash-looping worker is left alone before the next request may
+/// cold start it again, doubling with every further crash.
+const CRASH_LOOP_BACKOFF_BASE: Duration = Duration::from_secs(5 * 60);
+const CRASH_LOOP_BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// An isolate that ran at least this long before crashing starts a new series
+/// of consecutive crashes.
+const CRASH_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// Recent crashes of the workers on one isolate thread.
+#[derive(Clone, Debug, Default)]
+pub struct CrashHistory {
+    crashes: Rc<RefCell<HashMap<String, Crashes>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Crashes {
+    consecutive: u32,
+    restart_at: Instant,
+}
+
+impl Crashes {
+    fn is_crash_looping(&self, now: Instant) -> bool {
+        self.consecutive >= CRASH_LOOP_THRESHOLD && now < self.restart_at
+    }
+}
+
+impl CrashHistory {
+    /// Fails with [ServiceUnavailable] while `host_slug` is crash-looping or
+    /// backing off.
+    pub fn check(&self, host_slug: &str) -> Result<(), AnyError> {
+        let crashes = match self.crashes.borrow().get(host_slug) {
+            Some(c) => *c,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        if crashes.is_crash_looping(now) {
+            return Err(ServiceUnavailable(format!(
+                "worker is crash-looping after {} consecutive crashes, retrying in {}s",
+                crashes.consecutive,
+                (crashes.restart_at - now).as_secs()
+            ))
+            .into());
+        }
+        if now < crashes.restart_at {
+            return Err(ServiceUnavailable(format!(
+                "worker crashed, restarting in {}ms",
+                (crashes.restart_at - now).as_millis()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn is_crash_looping(&self, host_slug: &str) -> bool {
+        self.crashes
+            .borrow()
+            .get(host_slug)
+            .map_or(false, |c| c.is_crash_looping(Instant::now()))
+    }
+
+    /// Forgets the crashes of `host_slug`, e.g. after it was redeployed.
+    pub fn forget(&self, host_slug: &str) {
+        self.crashes.borrow_mut().remove(host_slug);
+    }
+
+    fn record(&self, host_slug: &str, started_at: Instant) -> Crashes {
+        let now = Instant::now();
+        let mut crashes = self.crashes.borrow_mut();
+        let entry = crashes.entry(host_slug.to_string()).or_insert(Crashes {
+            consecutive: 0,
+            restart_at: now,
+        });
+        if now.duration_since(started_at) >= CRASH_RESET_AFTER {
+            entry.consecutive = 0;
+        }
+        entry.consecutive += 1;
+        entry.restart_at = now + backoff(entry.consecutive);
+        *entry
+    }
+}
+
+/// No delay for the first crash, then doubling up to [BACKOFF_MAX]. Once
+/// crash-looping, starting over from [CRASH_LOOP_BACKOFF_BASE].
+fn backoff(consecutive: u32) -> Duration {
+    match consecutive {
+        0 | 1 => Duration::ZERO,
+        n if n >= CRASH_LOOP_THRESHOLD => CRASH_LOOP_BACKOFF_BASE
+            .saturating_mul(1 << (n - CRASH_LOOP_THRESHOLD).min(16))
+            .min(CRASH_LOOP_BACKOFF_MAX),
+        n => BACKOFF_BASE
+            .saturating_mul(1 << (n - 2).min(16))
+            .min(BACKOFF_MAX),
+    }
+}
+
+/// Handles the exit of isolate `id`, started at `started_at` for `host_slug`.
+/// Isolates that were drained or replaced exit as expected, and isolates
+/// terminated for exceeding a limit are only unregistered. Any other exit is a
+/// crash: the isolate is unregistered and restarted after a backoff.
+pub fn isolate_exited(
+    state: &IsolateManager,
+    host_slug: &str,
+    id: u64,
+    started_at: Instant,
+    result: Result<(), AnyError>,
+    stats: &IsolateStats,
+) {
+    let registered = state
+        .get_running_isolate(host_slug)
+        .map_or(false, |w| w.id == id);
+    match (&result, stats.exit_reason.get()) {
+        (Ok(()), _) if !registered => return,
+        (Ok(()), _) => println!("worker {host_slug} exited unexpectedly"),
+        (Err(_), Some(reason)) => println!("worker {host_slug} terminated: {}", reason.message()),
+        (Err(e), None) => println!("user code failed: {e}"),
+    }
+    if !registered {
+        return;
+    }
+    state.remove_isolate_if_id(host_slug, id);
+    if let Some(ExitReason::CpuLimit | ExitReason::HeapLimit) = stats.exit_reason.get() {
+        return;
+    }
+
+    let crashes = state.crashes.record(host_slug, started_at);
+    if crashes.consecutive >= CRASH_LOOP_THRESHOLD {
+        println!(
+            "worker {host_slug} is crash-looping after {} consecutive crashes, not restarting for {}s",
+            crashes.consecutive,
+            backoff(crashes.consecutive).as_secs()
+        );
+        return;
+    }
+    let mut state = state.clone();
+    let host_slug = host_slug.to_string();
+    tokio::task::spawn_local(async move {
+        tokio::time::sleep_until(crashes.restart_at).await;
+        // a request may have started it in the meantime
+        if state.get_running_isolate(&host_slug).is_some() {
+            return;
+        }
+        println!(
+            "restarting worker {host_slug} after {} consecutive crashes",
+            crashes.consecutive
+        );
+        if let Err(e) = router::cold_start(&mut state, &host_slug, None).await {
+            println!("failed to restart worker {host_slug}: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(backoff(0), Duration::ZERO);
+        assert_eq!(backoff(1), Duration::ZERO);
+        assert_eq!(backoff(2), BACKOFF_BASE);
+        assert_eq!(backoff(3), BACKOFF_BASE * 2);
+        assert_eq!(backoff(4), BACKOFF_BASE * 4);
+        assert_eq!(backoff(5), CRASH_LOOP_BACKOFF_BASE);
+        assert_eq!(backoff(6), CRASH_LOOP_BACKOFF_BASE * 2);
+        assert_eq!(backoff(u32::MAX), CRASH_LOOP_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn crash_loop_expires_after_backoff() {
+        let history = CrashHistory::default();
+        let started_at = Instant::now();
+        let mut crashes = history.record("hello", started_at);
+        for _ in 1..CRASH_LOOP_THRESHOLD {
+            crashes = history.record("hello", started_at);
+        }
+        assert!(history.is_crash_looping("hello"));
+        assert!(history.check("hello").is_err());
+        assert!(crashes.is_crash_looping(crashes.restart_at - Duration::from_secs(1)));
+        assert!(!crashes.is_crash_looping(crashes.restart_at));
+    }
+}
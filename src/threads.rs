@@ -0,0 +1,341 @@
+//! Isolate threads. Each thread runs its own current-thread runtime and
+//! `LocalSet` with an [IsolateManager] for the host slugs pinned to it. The
+//! ingress hands work to the owning thread through a channel.
+//!
+//! The limits of [config::IsolatesConfig] hold across all threads. Each thread
+//! publishes what its isolates use to [Usage], and a cold start that would
+//! exceed the limits asks the thread holding the isolate to evict to tear it
+//! down.
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use deno_runtime::deno_core::anyhow::anyhow;
+use deno_runtime::deno_core::error::AnyError;
+use deno_runtime::deno_core::futures::future::LocalBoxFuture;
+use deno_runtime::deno_core::futures::FutureExt;
+use tokio::sync::{mpsc, oneshot, Mutex, OwnedMutexGuard};
+
+use crate::access_log::AccessLog;
+use crate::metrics::Metrics;
+use crate::trace::Tracer;
+use crate::{config, store, watchdog, IsolateManager, IDLE_CHECK_INTERVAL};
+
+type Job = Box<dyn FnOnce(IsolateManager) -> LocalBoxFuture<'static, ()> + Send>;
+
+#[derive(Clone, Debug)]
+pub struct IsolateThreads {
+    pub store: store::Store,
+    pub metrics: Arc<Metrics>,
+    pub tracer: Tracer,
+    pub usage: Arc<Usage>,
+    threads: Arc<Vec<mpsc::UnboundedSender<Job>>>,
+}
+
+impl IsolateThreads {
+    /// Starts `limits.thread_count()` isolate threads, enforcing `limits`
+    /// together.
+    pub fn spawn(
+        store: store::Store,
+        limits: config::IsolatesConfig,
+        watchdog: watchdog::Watchdog,
+        access_log: Arc<AccessLog>,
+        metrics: Arc<Metrics>,
+        tracer: Tracer,
+    ) -> Result<Self, AnyError> {
+        let count = limits.thread_count();
+        let next_isolate_id = Arc::new(AtomicU64::new(0));
+        // every thread can hand work to every other, see [Usage]
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            (0..count).map(|_| mpsc::unbounded_channel()).unzip();
+        let threads = IsolateThreads {
+            store,
+            metrics,
+            tracer,
+            usage: Arc::new(Usage::new(count)),
+            threads: Arc::new(senders),
+        };
+        for (index, jobs_rx) in receivers.into_iter().enumerate() {
+            let state = ThreadState {
+                index,
+                threads: threads.clone(),
+                limits,
+                watchdog: watchdog.clone(),
+                next_isolate_id: next_isolate_id.clone(),
+                access_log: access_log.clone(),
+            };
+            std::thread::Builder::new()
+                .name(format!("openedge-isolates-{index}"))
+                .spawn(move || run_thread(state, jobs_rx))?;
+        }
+        println!("running isolates on {count} threads");
+        Ok(threads)
+    }
+
+    /// Runs `f` on the thread `host_slug` is pinned to and returns its output.
+    pub async fn run_for<F, Fut, T>(&self, host_slug: &str, f: F) -> Result<T, AnyError>
+    where
+        F: FnOnce(IsolateManager) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + 'static,
+        T: Send + 'static,
+    {
+        self.run_on(self.thread_index(host_slug), f).await
+    }
+
+    /// Runs `f` on isolate thread `index` and returns its output.
+    pub async fn run_on<F, Fut, T>(&self, index: usize, f: F) -> Result<T, AnyError>
+    where
+        F: FnOnce(IsolateManager) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.send(index, move |state| async move {
+            let _ = tx.send(f(state).await);
+        })?;
+        rx.await
+            .map_err(|_| anyhow!("isolate thread dropped the job"))
+    }
+
+    /// Like [IsolateThreads::run_for], without waiting for `f` to run.
+    pub fn spawn_for<F, Fut>(&self, host_slug: &str, f: F) -> Result<(), AnyError>
+    where
+        F: FnOnce(IsolateManager) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.send(self.thread_index(host_slug), f)
+    }
+
+    /// Runs `f` on every isolate thread and returns the outputs, in thread
+    /// order.
+    pub async fn run_on_each<F, Fut, T>(&self, f: F) -> Result<Vec<T>, AnyError>
+    where
+        F: FnOnce(IsolateManager) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = T> + 'static,
+        T: Send + 'static,
+    {
+        let mut outputs = Vec::with_capacity(self.threads.len());
+        for index in 0..self.threads.len() {
+            let (tx, rx) = oneshot::channel();
+            let f = f.clone();
+            self.send(index, move |state| async move {
+                let _ = tx.send(f(state).await);
+            })?;
+            outputs.push(rx);
+        }
+        let mut results = Vec::with_capacity(outputs.len());
+        for rx in outputs {
+            results.push(
+                rx.await
+                    .map_err(|_| anyhow!("isolate thread dropped the job"))?,
+            );
+        }
+        Ok(results)
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads.len()
+    }
+
+    fn send<F, Fut>(&self, index: usize, f: F) -> Result<(), AnyError>
+    where
+        F: FnOnce(IsolateManager) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let job: Job = Box::new(move |state| f(state).boxed_local());
+        self.threads[index]
+            .send(job)
+            .map_err(|_| anyhow!("isolate thread exited"))
+    }
+
+    /// See [IsolateManager::worker_changed].
+    pub fn worker_changed(&self, host_slug: &str) {
+        let host_slug = host_slug.to_string();
+        let _ = self.spawn_for(&host_slug.clone(), move |state| async move {
+            state.worker_changed(&host_slug);
+        });
+    }
+
+    fn thread_index(&self, host_slug: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        host_slug.hash(&mut hasher);
+        (hasher.finish() % self.threads.len() as u64) as usize
+    }
+}
+
+/// What an isolate thread is started with.
+pub struct ThreadState {
+    pub index: usize,
+    pub threads: IsolateThreads,
+    pub limits: config::IsolatesConfig,
+    pub watchdog: watchdog::Watchdog,
+    /// Shared by all threads so isolate ids stay unique.
+    pub next_isolate_id: Arc<AtomicU64>,
+    pub access_log: Arc<AccessLog>,
+}
+
+/// What the isolates of every thread use, as last published by the thread.
+#[derive(Debug)]
+pub struct Usage {
+    /// Held while making room for a new isolate until it is registered, so
+    /// concurrent cold starts cannot take the same room.
+    admission: Arc<Mutex<()>>,
+    epoch: Instant,
+    threads: Vec<ThreadUsage>,
+}
+
+#[derive(Debug)]
+struct ThreadUsage {
+    /// Running, starting and draining isolates.
+    isolates: AtomicUsize,
+    heap_used: AtomicUsize,
+    /// Last request of the least recently used ready isolate, in nanoseconds
+    /// since [Usage::epoch], or `u64::MAX` if there is none.
+    lru: AtomicU64,
+    /// Id of the oldest draining isolate, or `u64::MAX` if there is none.
+    oldest_draining: AtomicU64,
+}
+
+/// What an isolate thread publishes to [Usage].
+pub struct ThreadSample {
+    pub isolates: usize,
+    pub heap_used: usize,
+    pub lru: Option<Instant>,
+    pub oldest_draining: Option<u64>,
+}
+
+/// Which isolate to tear down to make room for a new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eviction {
+    /// The oldest draining isolate, which no longer serves new requests.
+    Draining,
+    /// The least recently used running isolate that is ready.
+    LeastRecentlyUsed,
+}
+
+impl Usage {
+    fn new(threads: usize) -> Self {
+        Usage {
+            admission: Arc::new(Mutex::new(())),
+            epoch: Instant::now(),
+            threads: (0..threads)
+                .map(|_| ThreadUsage {
+                    isolates: AtomicUsize::new(0),
+                    heap_used: AtomicUsize::new(0),
+                    lru: AtomicU64::new(u64::MAX),
+                    oldest_draining: AtomicU64::new(u64::MAX),
+                })
+                .collect(),
+        }
+    }
+
+    pub async fn admit(&self) -> OwnedMutexGuard<()> {
+        self.admission.clone().lock_owned().await
+    }
+
+    pub fn publish(&self, thread: usize, sample: ThreadSample) {
+        let usage = &self.threads[thread];
+        usage.isolates.store(sample.isolates, Ordering::SeqCst);
+        usage.heap_used.store(sample.heap_used, Ordering::SeqCst);
+        let lru = sample.lru.map_or(u64::MAX, |at| {
+            let since = at.saturating_duration_since(self.epoch).as_nanos();
+            u64::try_from(since).unwrap_or(u64::MAX - 1)
+        });
+        usage.lru.store(lru, Ordering::SeqCst);
+        let oldest_draining = sample.oldest_draining.unwrap_or(u64::MAX);
+        usage
+            .oldest_draining
+            .store(oldest_draining, Ordering::SeqCst);
+    }
+
+    /// Isolates and heap bytes used across all threads.
+    pub fn total(&self) -> (usize, usize) {
+        self.threads.iter().fold((0, 0), |(isolates, heap), usage| {
+            (
+                isolates + usage.isolates.load(Ordering::SeqCst),
+                heap.saturating_add(usage.heap_used.load(Ordering::SeqCst)),
+            )
+        })
+    }
+
+    /// The thread holding the isolate to evict next: the oldest draining
+    /// isolate of all threads, or else the least recently used ready one.
+    pub fn eviction_candidate(&self) -> Option<(usize, Eviction)> {
+        let oldest = |field: fn(&ThreadUsage) -> &AtomicU64| {
+            self.threads
+                .iter()
+                .enumerate()
+                .map(|(index, usage)| (field(usage).load(Ordering::SeqCst), index))
+                .filter(|(value, _)| *value != u64::MAX)
+                .min()
+                .map(|(_, index)| index)
+        };
+        oldest(|usage| &usage.oldest_draining)
+            .map(|index| (index, Eviction::Draining))
+            .or_else(|| {
+                oldest(|usage| &usage.lru).map(|index| (index, Eviction::LeastRecentlyUsed))
+            })
+    }
+}
+
+fn run_thread(thread: ThreadState, mut jobs: mpsc::UnboundedReceiver<Job>) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build isolate thread runtime");
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&rt, async move {
+        let state = IsolateManager::new(thread);
+        state.warm_pool.refill();
+
+        let idle_state = state.clone();
+        tokio::task::spawn_local(async move {
+            let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                idle_state.evict_idle_isolates();
+            }
+        });
+
+        while let Some(job) = jobs.recv().await {
+            tokio::task::spawn_local(job(state.clone()));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(isolates: usize, lru: Option<Instant>, oldest_draining: Option<u64>) -> ThreadSample {
+        ThreadSample {
+            isolates,
+            heap_used: isolates * 1024,
+            lru,
+            oldest_draining,
+        }
+    }
+
+    #[test]
+    fn totals_and_evicts_across_threads() {
+        let usage = Usage::new(3);
+        assert_eq!(usage.eviction_candidate(), None);
+        let earlier = Instant::now();
+        let later = earlier + std::time::Duration::from_secs(1);
+        usage.publish(0, sample(1, Some(later), None));
+        usage.publish(1, sample(2, Some(earlier), None));
+        assert_eq!(usage.total(), (3, 3 * 1024));
+        assert_eq!(
+            usage.eviction_candidate(),
+            Some((1, Eviction::LeastRecentlyUsed))
+        );
+        // draining isolates go first, oldest first
+        usage.publish(0, sample(2, Some(later), Some(7)));
+        usage.publish(2, sample(1, None, Some(3)));
+        assert_eq!(usage.total(), (5, 5 * 1024));
+        assert_eq!(usage.eviction_candidate(), Some((2, Eviction::Draining)));
+    }
+}
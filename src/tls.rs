@@ -0,0 +1,159 @@
+//! TLS termination at the ingress. Certificates are loaded from disk at
+//! startup and picked per connection by the SNI hostname, see
+//! [crate::config::TlsConfig].
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use deno_runtime::deno_core::anyhow::anyhow;
+use deno_runtime::deno_core::error::AnyError;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{CertificateConfig, TlsConfig};
+use crate::Ingress;
+
+/// Clients that don't complete their handshake within this are disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates an acceptor serving the certificates of `resolver`, offering HTTP/2
+/// and HTTP/1.1 over ALPN.
+pub fn acceptor(resolver: Arc<SniResolver>) -> TlsAcceptor {
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(server_config))
+}
+
+/// Picks the certificate for the SNI hostname: an exact match, then a wildcard
+/// for its parent domain, then the catch-all `*`. Certificates can be added
+/// while serving, see [crate::acme].
+pub struct SniResolver {
+    certificates: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl SniResolver {
+    /// Loads the certificates of `config`. Paths are resolved against
+    /// `base_dir`.
+    pub fn load(config: &TlsConfig, base_dir: &Path) -> Result<Self, AnyError> {
+        let mut certificates = HashMap::new();
+        let mut errors = vec![];
+        for (hostname, paths) in &config.certificates {
+            match load_certificate(paths, base_dir) {
+                Ok(key) => {
+                    certificates.insert(hostname.to_ascii_lowercase(), Arc::new(key));
+                }
+                Err(e) => errors.push(format!("certificate \"{hostname}\": {e}")),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(anyhow!("invalid manifest:\n  {}", errors.join("\n  ")));
+        }
+        Ok(SniResolver {
+            certificates: RwLock::new(certificates),
+        })
+    }
+
+    /// Serves `key` for `hostname`, replacing any certificate it had.
+    pub fn insert(&self, hostname: &str, key: CertifiedKey) {
+        self.certificates
+            .write()
+            .unwrap()
+            .insert(hostname.to_string(), Arc::new(key));
+    }
+
+    pub fn contains(&self, hostname: &str) -> bool {
+        self.certificates.read().unwrap().contains_key(hostname)
+    }
+
+    fn lookup(&self, hostname: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap();
+        if let Some(hostname) = hostname {
+            let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+            if let Some(key) = certificates.get(&hostname) {
+                return Some(key.clone());
+            }
+            if let Some((_, parent)) = hostname.split_once('.') {
+                if let Some(key) = certificates.get(&format!("*.{parent}")) {
+                    return Some(key.clone());
+                }
+            }
+        }
+        certificates.get("*").cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.lookup(client_hello.server_name())
+    }
+}
+
+fn load_certificate(paths: &CertificateConfig, base_dir: &Path) -> Result<CertifiedKey, AnyError> {
+    load_pem_files(&base_dir.join(&paths.cert), &base_dir.join(&paths.key))
+}
+
+/// Loads a PEM certificate chain, leaf first, and its PEM private key.
+pub fn load_pem_files(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, AnyError> {
+    let chain = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path).map_err(|e| anyhow!("{}: {e}", cert_path.display()))?,
+    ))?;
+    if chain.is_empty() {
+        return Err(anyhow!("{} contains no certificates", cert_path.display()));
+    }
+
+    let mut reader =
+        BufReader::new(File::open(key_path).map_err(|e| anyhow!("{}: {e}", key_path.display()))?);
+    let key = rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("{} contains no private key", key_path.display()))?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| anyhow!("{}: unsupported private key", key_path.display()))?;
+
+    Ok(CertifiedKey::new(
+        chain.into_iter().map(Certificate).collect(),
+        signing_key,
+    ))
+}
+
+/// Accepts TLS connections on `listener` and serves them like the plaintext
+/// ingress, passing on the SNI hostname for routing.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    ingress: Ingress,
+) -> Result<(), AnyError> {
+    loop {
+        let (stream, client) = listener.accept().await?;
+
+        let acceptor = acceptor.clone();
+        let ingress = ingress.clone();
+        tokio::task::spawn_local(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        println!("TLS handshake with {client} failed: {e}");
+                        return;
+                    }
+                    Err(_) => return,
+                };
+            let sni = stream.get_ref().1.sni_hostname().map(String::from);
+            crate::serve_connection(stream, ingress, client.ip(), sni).await;
+        });
+    }
+}
@@ -0,0 +1,506 @@
+//! Distributed tracing. Incoming requests continue the trace of their W3C
+//! `traceparent` header, or start a new one, and the ingress records spans for
+//! the request, its routing, cold starts and the isolate's execution. The
+//! isolate's span is passed on in the request's `traceparent`, which `fetch.js`
+//! injects into the worker's outbound `fetch` calls.
+//!
+//! Spans are exported in batches to an OTLP/HTTP collector as JSON. Spans
+//! finished while the exporter is too far behind are dropped and counted.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use deno_runtime::deno_core::anyhow::anyhow;
+use deno_runtime::deno_core::error::AnyError;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, Uri};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::config::{TracingConfig, DEFAULT_SERVICE_NAME};
+
+/// Wraps `fetch` in every isolate, run after `console.js`.
+pub const FETCH_JS: &str = include_str!("fetch.js");
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BATCH_SIZE: usize = 512;
+/// Finished spans waiting for the exporter before further ones are dropped.
+const MAX_QUEUED_SPANS: usize = 8 * MAX_BATCH_SIZE;
+
+/// The trace a span belongs to and its own id, as carried by `traceparent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parses a `traceparent` header. Invalid headers are ignored, as the spec
+    /// requires. Later versions are parsed as far as version 00 goes: they may
+    /// only append fields.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let version = decode_hex::<1>(version)?[0];
+        let valid_version = match version {
+            0 => parts.next().is_none(),
+            0xff => false,
+            _ => true,
+        };
+        if !valid_version {
+            return None;
+        }
+        let trace_id = decode_hex::<16>(trace_id)?;
+        let span_id = decode_hex::<8>(span_id)?;
+        let flags = decode_hex::<1>(flags)?[0];
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal,
+    Server,
+}
+
+impl SpanKind {
+    /// The OTLP `SpanKind` value.
+    fn otlp(&self) -> u8 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<u64> for AttributeValue {
+    fn from(value: u64) -> Self {
+        AttributeValue::Int(value as i64)
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+/// Starts spans and hands finished ones to the exporter. Cheap to clone and
+/// shared by the ingress and every isolate thread.
+#[derive(Clone, Debug)]
+pub struct Tracer {
+    exporter: Option<Exporter>,
+}
+
+/// The queue of finished spans, see [export].
+#[derive(Clone, Debug)]
+struct Exporter {
+    spans: mpsc::Sender<Span>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Exporter {
+    fn new() -> (Self, mpsc::Receiver<Span>) {
+        let (spans, queued) = mpsc::channel(MAX_QUEUED_SPANS);
+        let exporter = Exporter {
+            spans,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (exporter, queued)
+    }
+
+    fn send(&self, span: Span) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.spans.try_send(span) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Tracer {
+    /// Creates a tracer for `config`, spawning its exporter on the current
+    /// `LocalSet` if an endpoint is configured.
+    pub fn spawn(config: &TracingConfig) -> Result<Self, AnyError> {
+        let endpoint = match &config.otlp_endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(Tracer { exporter: None }),
+        };
+        let url: Uri = format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+            .parse()
+            .map_err(|e| anyhow!("invalid tracing.otlp_endpoint {endpoint:?}: {e}"))?;
+        if url.scheme_str() != Some("http") {
+            return Err(anyhow!(
+                "tracing.otlp_endpoint must be an http:// URL, got {endpoint:?}"
+            ));
+        }
+        let service_name = config
+            .service_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+        let (exporter, spans) = Exporter::new();
+        tokio::task::spawn_local(export(url, service_name, spans, exporter.dropped.clone()));
+        println!("exporting spans to {endpoint}");
+        Ok(Tracer {
+            exporter: Some(exporter),
+        })
+    }
+
+    /// Starts a span as a child of `parent`, or of a new trace without one.
+    pub fn start(&self, name: &'static str, kind: SpanKind, parent: Option<&TraceContext>) -> Span {
+        let context = match parent {
+            Some(parent) => TraceContext {
+                span_id: new_span_id(),
+                ..*parent
+            },
+            None => TraceContext {
+                trace_id: *uuid::Uuid::new_v4().as_bytes(),
+                span_id: new_span_id(),
+                sampled: self.exporter.is_some(),
+            },
+        };
+        Span {
+            exporter: self.exporter.clone(),
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+            name,
+            kind,
+            start: SystemTime::now(),
+            end: None,
+            attributes: vec![],
+            error: false,
+        }
+    }
+}
+
+/// A span in progress. It is exported once [Span::end] is called; spans that
+/// are dropped instead are discarded.
+#[derive(Debug)]
+pub struct Span {
+    exporter: Option<Exporter>,
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    error: bool,
+}
+
+impl Span {
+    pub fn context(&self) -> &TraceContext {
+        &self.context
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    pub fn end(mut self) {
+        if !self.context.sampled {
+            return;
+        }
+        if let Some(exporter) = self.exporter.take() {
+            self.end = Some(SystemTime::now());
+            exporter.send(self);
+        }
+    }
+
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    AttributeValue::String(s) => json!({ "stringValue": s }),
+                    // int64 is encoded as a string in OTLP/JSON
+                    AttributeValue::Int(i) => json!({ "intValue": i.to_string() }),
+                };
+                json!({ "key": key, "value": value })
+            })
+            .collect();
+        let mut span = json!({
+            "traceId": encode_hex(&self.context.trace_id),
+            "spanId": encode_hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind.otlp(),
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end.unwrap_or(self.start)),
+            "attributes": attributes,
+            // STATUS_CODE_ERROR or STATUS_CODE_UNSET
+            "status": { "code": if self.error { 2 } else { 0 } },
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(encode_hex(parent));
+        }
+        span
+    }
+}
+
+/// Sends finished spans to `url` every [EXPORT_INTERVAL], or as soon as
+/// [MAX_BATCH_SIZE] have accumulated, and the rest once all tracers are gone.
+/// A batch that fails to export within [EXPORT_TIMEOUT] is dropped.
+async fn export(
+    url: Uri,
+    service_name: String,
+    mut spans: mpsc::Receiver<Span>,
+    dropped: Arc<AtomicU64>,
+) {
+    let client = Client::new();
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+    let mut closed = false;
+    while !closed {
+        tokio::select! {
+            span = spans.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < MAX_BATCH_SIZE {
+                        continue;
+                    }
+                }
+                None => closed = true,
+            },
+            _ = interval.tick() => {}
+        }
+        match dropped.swap(0, Ordering::Relaxed) {
+            0 => {}
+            n => println!("dropped {n} spans, the exporter could not keep up"),
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": service_name } },
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "openedge" },
+                    "spans": batch.drain(..).map(|s| s.to_otlp()).collect::<Vec<_>>(),
+                }],
+            }],
+        });
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&body).unwrap_or_default()))
+            .unwrap();
+        match tokio::time::timeout(EXPORT_TIMEOUT, client.request(req)).await {
+            Ok(Ok(resp)) if resp.status().is_success() => {}
+            Ok(Ok(resp)) => println!(
+                "failed to export spans: collector answered {}",
+                resp.status()
+            ),
+            Ok(Err(e)) => println!("failed to export spans: {e}"),
+            Err(_) => println!("failed to export spans: collector did not answer in time"),
+        }
+    }
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut id = [0; 8];
+    id.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..8]);
+    id
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(hex, "{b:02x}");
+    }
+    hex
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    // upper case is invalid in traceparent
+    if hex.len() != N * 2 || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_traceparent() {
+        let context = TraceContext::parse(TRACEPARENT).unwrap();
+        assert_eq!(
+            encode_hex(&context.trace_id),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(encode_hex(&context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), TRACEPARENT);
+    }
+
+    #[test]
+    fn parses_unsampled_traceparent() {
+        let context =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap();
+        assert!(!context.sampled);
+    }
+
+    #[test]
+    fn parses_later_traceparent_versions() {
+        for traceparent in [
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-03-what-the-future-holds",
+        ] {
+            let context = TraceContext::parse(traceparent).unwrap();
+            assert!(context.sampled);
+            assert_eq!(context.to_traceparent(), TRACEPARENT);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_traceparents() {
+        for traceparent in [
+            "",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "0-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0g",
+        ] {
+            assert!(TraceContext::parse(traceparent).is_none(), "{traceparent}");
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_spans_to_collector() {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::convert::Infallible;
+
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        let collector =
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+                let requests_tx = requests_tx.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let requests_tx = requests_tx.clone();
+                        async move {
+                            let path = req.uri().path().to_string();
+                            let body = hyper::body::to_bytes(req.into_body()).await?;
+                            let _ = requests_tx.send((path, body));
+                            Ok::<_, hyper::Error>(hyper::Response::new(Body::empty()))
+                        }
+                    }))
+                }
+            }));
+        let url = format!("http://{}/v1/traces", collector.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(collector);
+
+        let (exporter, spans) = Exporter::new();
+        let tracer = Tracer {
+            exporter: Some(exporter.clone()),
+        };
+        let parent = TraceContext::parse(TRACEPARENT).unwrap();
+        let mut span = tracer.start("request", SpanKind::Server, Some(&parent));
+        span.set_attribute("http.status_code", 200u16);
+        let context = *span.context();
+        span.end();
+        // closing the queue flushes the batch
+        drop((tracer, exporter));
+        export(url, "edge".to_string(), spans, Arc::new(AtomicU64::new(0))).await;
+
+        let (path, body) = requests.recv().await.unwrap();
+        assert_eq!(path, "/v1/traces");
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "edge"
+        );
+        let span = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "request");
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["spanId"], encode_hex(&context.span_id));
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["attributes"][0]["value"]["intValue"], "200");
+    }
+
+    #[test]
+    fn drops_spans_when_queue_is_full() {
+        let (exporter, _spans) = Exporter::new();
+        let tracer = Tracer {
+            exporter: Some(exporter.clone()),
+        };
+        let parent = TraceContext::parse(TRACEPARENT).unwrap();
+        for _ in 0..MAX_QUEUED_SPANS + 3 {
+            tracer
+                .start("request", SpanKind::Server, Some(&parent))
+                .end();
+        }
+        assert_eq!(exporter.dropped.load(Ordering::Relaxed), 3);
+    }
+}
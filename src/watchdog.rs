@@ -0,0 +1,161 @@
+//! Terminates isolates whose JavaScript runs past a deadline. JS execution
+//! blocks the thread driving the isolate, so the deadline has to be enforced
+//! from another thread through V8's [v8::IsolateHandle::terminate_execution].
+//!
+//! Isolates arm and disarm the watchdog around every event loop turn, so doing
+//! so only stores their deadline in an atomic. The watchdog thread scans the
+//! deadlines of all isolates and sleeps until the earliest; arming only wakes it
+//! when the new deadline is earlier still.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::Thread;
+use std::time::{Duration, Instant};
+
+use deno_runtime::deno_core::v8;
+
+/// Deadline of an isolate that is not armed.
+const DISARMED: u64 = u64::MAX;
+
+#[derive(Clone, Debug)]
+pub struct Watchdog {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Deadlines are stored as nanoseconds since `epoch`.
+    epoch: Instant,
+    watched: Mutex<HashMap<u64, Arc<Deadline>>>,
+    /// When the watchdog thread wakes up next, see [WatchedIsolate::arm].
+    wake_at: AtomicU64,
+    thread: OnceLock<Thread>,
+    next_id: AtomicU64,
+}
+
+struct Deadline {
+    at: AtomicU64,
+    handle: v8::IsolateHandle,
+    terminated: AtomicBool,
+}
+
+impl std::fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deadline")
+            .field("at", &self.at)
+            .field("terminated", &self.terminated)
+            .finish()
+    }
+}
+
+impl Watchdog {
+    /// Starts the watchdog thread.
+    pub fn spawn() -> Self {
+        let inner = Arc::new(Inner {
+            epoch: Instant::now(),
+            watched: Mutex::new(HashMap::new()),
+            wake_at: AtomicU64::new(DISARMED),
+            thread: OnceLock::new(),
+            next_id: AtomicU64::new(0),
+        });
+        let thread_inner = inner.clone();
+        let thread = std::thread::Builder::new()
+            .name("openedge-watchdog".to_string())
+            .spawn(move || thread_inner.run())
+            .expect("spawn watchdog thread");
+        let _ = inner.thread.set(thread.thread().clone());
+        Watchdog { inner }
+    }
+
+    pub fn watch(&self, handle: v8::IsolateHandle) -> WatchedIsolate {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let deadline = Arc::new(Deadline {
+            at: AtomicU64::new(DISARMED),
+            handle,
+            terminated: AtomicBool::new(false),
+        });
+        self.inner
+            .watched
+            .lock()
+            .unwrap()
+            .insert(id, deadline.clone());
+        WatchedIsolate {
+            id,
+            deadline,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Inner {
+    fn now(&self) -> u64 {
+        u64::try_from(self.epoch.elapsed().as_nanos()).unwrap_or(DISARMED)
+    }
+
+    fn run(&self) {
+        loop {
+            // arming from here on wakes the thread unless it is found by the scan
+            self.wake_at.store(DISARMED, Ordering::SeqCst);
+            let now = self.now();
+            let mut next = DISARMED;
+            for deadline in self.watched.lock().unwrap().values() {
+                let at = deadline.at.load(Ordering::SeqCst);
+                if at <= now {
+                    deadline.terminated.store(true, Ordering::SeqCst);
+                    deadline.handle.terminate_execution();
+                    let _ = deadline.at.compare_exchange(
+                        at,
+                        DISARMED,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    );
+                } else {
+                    next = next.min(at);
+                }
+            }
+            let wake_at = self.wake_at.fetch_min(next, Ordering::SeqCst).min(next);
+            match wake_at {
+                DISARMED => std::thread::park(),
+                at => std::thread::park_timeout(Duration::from_nanos(at.saturating_sub(now))),
+            }
+        }
+    }
+}
+
+/// An isolate registered with the [Watchdog]. JS may only run past a deadline
+/// while it is armed.
+pub struct WatchedIsolate {
+    id: u64,
+    deadline: Arc<Deadline>,
+    inner: Arc<Inner>,
+}
+
+impl WatchedIsolate {
+    /// Terminates execution if it is still running `timeout` from now.
+    pub fn arm(&self, timeout: Duration) {
+        let timeout = u64::try_from(timeout.as_nanos()).unwrap_or(DISARMED);
+        let at = self.inner.now().saturating_add(timeout).min(DISARMED - 1);
+        self.deadline.at.store(at, Ordering::SeqCst);
+        if self.inner.wake_at.fetch_min(at, Ordering::SeqCst) > at {
+            if let Some(thread) = self.inner.thread.get() {
+                thread.unpark();
+            }
+        }
+    }
+
+    pub fn disarm(&self) {
+        self.deadline.at.store(DISARMED, Ordering::SeqCst);
+    }
+
+    /// Whether the watchdog has terminated execution. Once set, the isolate must
+    /// be discarded.
+    pub fn terminated(&self) -> bool {
+        self.deadline.terminated.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for WatchedIsolate {
+    fn drop(&mut self) {
+        self.inner.watched.lock().unwrap().remove(&self.id);
+    }
+}
@@ -1,42 +1,305 @@
 use deno_runtime::{
     deno_broadcast_channel::InMemoryBroadcastChannel, deno_core::anyhow::anyhow,
-    deno_core::error::AnyError, deno_core::ModuleSpecifier, deno_web::BlobStore,
-    worker::WorkerOptions, BootstrapOptions,
+    deno_core::error::AnyError, deno_core::futures::future::poll_fn, deno_core::ModuleSpecifier,
+    deno_web::BlobStore, worker::WorkerOptions, BootstrapOptions,
 };
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
 
+use crate::console::{self, WorkerConsole};
+use crate::dispatch::{self, IsolateRequest};
 use crate::loader;
 use crate::loader::OnlyLoadWrapperImports;
+use crate::located_script_name;
 use crate::runtime::runtime::{Permissions, Runtime};
+use crate::store;
+use crate::trace;
+use crate::watchdog::{Watchdog, WatchedIsolate};
 
-pub async fn run_usercode(main_module: ModuleSpecifier, port: u16) -> Result<(), AnyError> {
-    let mut worker = instance(main_module.clone(), port)?;
+/// Longest a single turn of the event loop, or the evaluation of the user
+/// module, may run JavaScript when the worker sets no CPU limit. Without a
+/// deadline, a synchronous loop would wedge the isolate thread for good.
+const MAX_TURN_CPU: Duration = Duration::from_secs(10);
+
+/// Why the runtime shut an isolate down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    CpuLimit,
+    HeapLimit,
+    /// The user module did not evaluate within the startup timeout.
+    StartupTimeout,
+}
+
+impl ExitReason {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ExitReason::CpuLimit => "exceeded CPU limit",
+            ExitReason::HeapLimit => "exceeded memory limit",
+            ExitReason::StartupTimeout => "did not start in time",
+        }
+    }
+}
+
+/// Resource usage of a running isolate, updated by the isolate as it runs.
+#[derive(Debug, Default)]
+pub struct IsolateStats {
+    pub heap_used: Cell<usize>,
+    pub exit_reason: Cell<Option<ExitReason>>,
+    /// Time spent running JS while requests were in flight, split evenly
+    /// between the requests in flight at the time.
+    cpu_clock: Cell<Duration>,
+    /// The value of `cpu_clock` when each in-flight request arrived.
+    in_flight: RefCell<HashMap<u64, Duration>>,
+    next_request_id: Cell<u64>,
+}
+
+impl IsolateStats {
+    pub fn request_started(&self) -> u64 {
+        let id = self.next_request_id.get();
+        self.next_request_id.set(id + 1);
+        self.in_flight.borrow_mut().insert(id, self.cpu_clock.get());
+        id
+    }
+
+    pub fn request_finished(&self, id: u64) {
+        self.in_flight.borrow_mut().remove(&id);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.borrow().len()
+    }
+
+    /// CPU time charged to the longest running in-flight request.
+    fn max_request_cpu(&self) -> Option<Duration> {
+        let oldest = self.in_flight.borrow().values().min().copied()?;
+        Some(self.cpu_clock.get() - oldest)
+    }
+
+    fn charge_cpu(&self, elapsed: Duration, in_flight: usize) {
+        if in_flight > 0 {
+            let share = elapsed / u32::try_from(in_flight).unwrap_or(u32::MAX);
+            self.cpu_clock.set(self.cpu_clock.get() + share);
+        }
+    }
+}
+
+/// Runtimes that have been bootstrapped but have not loaded a user module yet.
+/// Cold starts take one instead of paying for [Runtime::bootstrap] on the
+/// request path, and the pool is refilled in the background.
+pub struct WarmPool {
+    runtimes: RefCell<Vec<Runtime>>,
+    size: usize,
+    refilling: Cell<bool>,
+}
+
+impl WarmPool {
+    pub fn new(size: usize) -> Rc<Self> {
+        Rc::new(WarmPool {
+            runtimes: RefCell::new(Vec::with_capacity(size)),
+            size,
+            refilling: Cell::new(false),
+        })
+    }
+
+    pub fn take(self: &Rc<Self>) -> Option<Runtime> {
+        let runtime = self.runtimes.borrow_mut().pop();
+        self.refill();
+        runtime
+    }
+
+    /// Bootstraps runtimes until the pool is full again, one per scheduler turn
+    /// so the ingress keeps serving in between.
+    pub fn refill(self: &Rc<Self>) {
+        if self.refilling.get() || self.runtimes.borrow().len() >= self.size {
+            return;
+        }
+        self.refilling.set(true);
+        let pool = self.clone();
+        tokio::task::spawn_local(async move {
+            while pool.runtimes.borrow().len() < pool.size {
+                tokio::task::yield_now().await;
+                match instance(loader::wrapper_specifier(), None) {
+                    Ok(runtime) => pool.runtimes.borrow_mut().push(runtime),
+                    Err(e) => {
+                        println!("failed to prewarm runtime: {e}");
+                        break;
+                    }
+                }
+            }
+            pool.refilling.set(false);
+        });
+    }
+}
+
+impl fmt::Debug for WarmPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WarmPool")
+            .field("available", &self.runtimes.borrow().len())
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// How a starting isolate reports that it is ready, and how long it may take.
+pub struct Startup {
+    /// Set once the user module has been evaluated and the isolate starts
+    /// pulling requests.
+    pub ready: watch::Sender<bool>,
+    /// The isolate is terminated if evaluating the module has not completed by
+    /// then. The router times out waiting for `ready` as well, but that timer
+    /// cannot fire while the module blocks the thread.
+    pub timeout: Duration,
+}
+
+/// Runs `usercode` until `requests` is closed and every in-flight request has
+/// completed, reporting its start through `startup`. Console output goes to
+/// `console`.
+///
+/// `prewarmed` is used instead of bootstrapping a new runtime when given. It
+/// must have been created with the worker's heap limit.
+pub async fn run_usercode(
+    usercode: store::Worker,
+    prewarmed: Option<Runtime>,
+    requests: mpsc::UnboundedReceiver<IsolateRequest>,
+    startup: Startup,
+    stats: Rc<IsolateStats>,
+    watchdog: Watchdog,
+    console: WorkerConsole,
+) -> Result<(), AnyError> {
+    let started_at = Instant::now();
+    let main_module = usercode.module;
+    let mut worker = match prewarmed {
+        Some(runtime) => runtime,
+        None => instance(
+            main_module.clone(),
+            usercode
+                .limits
+                .max_heap_mb
+                .map(|mb| mb.saturating_mul(1024 * 1024)),
+        )?,
+    };
+    let heap_stats = stats.clone();
+    worker.terminate_near_heap_limit(move || {
+        heap_stats.exit_reason.set(Some(ExitReason::HeapLimit));
+    });
+    dispatch::attach(
+        &mut worker.js_runtime.op_state().borrow_mut(),
+        requests,
+        stats.clone(),
+    );
+    console::attach(&mut worker.js_runtime.op_state().borrow_mut(), console);
+    let watched = watchdog.watch(worker.js_runtime.v8_isolate().thread_safe_handle());
+    let cpu_limit = usercode.limits.cpu_ms.map(Duration::from_millis);
 
     let region = std::env::var("FLY_REGION").unwrap_or_else(|_| "UNKNOWN".to_string());
-    let env_vars = vec![("REGION", region.as_str())];
-
-    let module_wrapper = loader::new_wrapper(&main_module, &env_vars, port);
-    let mod_id = worker
-        .js_runtime
-        .load_main_module(&module_wrapper.spec, Some(module_wrapper.code))
-        .await?;
-    worker.evaluate_module(mod_id).await?;
-    worker.run_event_loop(false).await?;
-    Ok(())
+    let mut env_vars: Vec<(&str, &str)> = usercode
+        .env_vars
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .filter(|(k, _)| *k != "REGION")
+        .collect();
+    env_vars.push(("REGION", region.as_str()));
+
+    let module_wrapper = loader::new_wrapper(&main_module, &env_vars);
+    // top-level code runs outside of the event loop turns watched below
+    let cpu_timeout = cpu_limit.unwrap_or(MAX_TURN_CPU);
+    let startup_timeout = startup.timeout.saturating_sub(started_at.elapsed());
+    watched.arm(cpu_timeout.min(startup_timeout));
+    let evaluated = async {
+        let mod_id = worker
+            .js_runtime
+            .load_main_module(&module_wrapper.spec, Some(module_wrapper.code))
+            .await?;
+        worker.evaluate_module(mod_id).await
+    }
+    .await;
+    watched.disarm();
+    if watched.terminated() {
+        let reason = if startup_timeout <= cpu_timeout {
+            ExitReason::StartupTimeout
+        } else {
+            ExitReason::CpuLimit
+        };
+        stats.exit_reason.set(Some(reason));
+    }
+    if let Some(reason) = stats.exit_reason.get() {
+        return Err(anyhow!(reason.message()));
+    }
+    evaluated?;
+    stats.heap_used.set(worker.used_heap_size());
+    // the router may have given up waiting, which is fine
+    let _ = startup.ready.send(true);
+    run_event_loop(&mut worker, &stats, cpu_limit, &watched).await
 }
 
-const RUNTIME_VERSION: &'static str = "0.0.1";
-const USER_AGENT: &'static str = "openedge-0.0.1";
+/// Drives the isolate's event loop, recording heap usage after every turn and
+/// terminating the isolate once an in-flight request exceeds `cpu_limit`, or
+/// once a turn outside of requests runs longer than `cpu_limit` or
+/// [MAX_TURN_CPU]. Fails with the isolate's [ExitReason] once it has been
+/// terminated.
+async fn run_event_loop(
+    worker: &mut Runtime,
+    stats: &IsolateStats,
+    cpu_limit: Option<Duration>,
+    watched: &WatchedIsolate,
+) -> Result<(), AnyError> {
+    poll_fn(|cx| {
+        let in_flight = stats.in_flight();
+        let started = Instant::now();
+        let turn_limit = match (cpu_limit, stats.max_request_cpu()) {
+            // the turn's time is split between all in-flight requests
+            (Some(limit), Some(used)) => limit
+                .saturating_sub(used)
+                .saturating_mul(u32::try_from(in_flight).unwrap_or(u32::MAX)),
+            _ => cpu_limit.unwrap_or(MAX_TURN_CPU),
+        };
+        watched.arm(turn_limit);
+        let result = worker.js_runtime.poll_event_loop(cx, false);
+        watched.disarm();
+        stats.charge_cpu(started.elapsed(), in_flight);
+        stats.heap_used.set(worker.used_heap_size());
+
+        let over_limit = matches!(
+            (cpu_limit, stats.max_request_cpu()),
+            (Some(limit), Some(used)) if used > limit
+        );
+        if watched.terminated() || over_limit {
+            stats.exit_reason.set(Some(ExitReason::CpuLimit));
+        }
+        if let Some(reason) = stats.exit_reason.get() {
+            return Poll::Ready(Err(anyhow!(reason.message())));
+        }
+        result
+    })
+    .await
+}
+
+const RUNTIME_VERSION: &str = "0.0.1";
+const USER_AGENT: &str = "openedge-0.0.1";
 
 fn get_error_class_name(e: &AnyError) -> &'static str {
     deno_runtime::errors::get_error_class_name(e).unwrap_or("Error")
 }
 
-pub fn instance(main_module: ModuleSpecifier, port: u16) -> Result<Runtime, AnyError> {
-    let module_loader = Rc::new(OnlyLoadWrapperImports::new());
+pub fn instance(
+    main_module: ModuleSpecifier,
+    max_heap_bytes: Option<usize>,
+) -> Result<Runtime, AnyError> {
+    instance_with_loader(main_module, max_heap_bytes, OnlyLoadWrapperImports::new())
+}
+
+pub fn instance_with_loader(
+    main_module: ModuleSpecifier,
+    max_heap_bytes: Option<usize>,
+    module_loader: OnlyLoadWrapperImports,
+) -> Result<Runtime, AnyError> {
+    let module_loader = Rc::new(module_loader);
     let create_web_worker_cb = Arc::new(|_| unimplemented!());
     let web_worker_event_cb = Arc::new(|_| unimplemented!());
 
@@ -56,7 +319,7 @@ pub fn instance(main_module: ModuleSpecifier, port: u16) -> Result<Runtime, AnyE
             user_agent: USER_AGENT.to_string(),
             inspect: false,
         },
-        extensions: vec![],
+        extensions: vec![dispatch::init(), console::init()],
         unsafely_ignore_certificate_errors: None,
         root_cert_store: None,
         seed: None,
@@ -77,25 +340,8 @@ pub fn instance(main_module: ModuleSpecifier, port: u16) -> Result<Runtime, AnyE
         compiled_wasm_module_store: None,
         stdio: Default::default(),
     };
-    let r = Runtime::bootstrap_from_options(
-        main_module,
-        Permissions {
-            allow_local_port: port,
-        },
-        options,
-    );
+    let mut r = Runtime::bootstrap_from_options(main_module, Permissions, options, max_heap_bytes);
+    r.execute_script(&located_script_name!(), console::CONSOLE_JS)?;
+    r.execute_script(&located_script_name!(), trace::FETCH_JS)?;
     Ok(r)
 }
-
-// TODO: this is very important (with respect to cold start times) and is currently extremely
-// suboptimal.
-pub async fn wait_until_dials(addr: SocketAddr) -> Result<(), AnyError> {
-    // TODO: add loop timeout
-    loop {
-        match tokio::net::TcpStream::connect(addr).await {
-            Ok(_) => return Ok(()),
-            Err(e) if e.kind() == ErrorKind::ConnectionRefused => continue,
-            Err(e) => return Err(anyhow!("worker failed readiness probe with bad error: {e}")),
-        }
-    }
-}